@@ -0,0 +1,81 @@
+use axum::extract::{Path, Query, State};
+use axum::response::{IntoResponse, Response};
+use http::HeaderMap;
+use protocol::customer_import::{self, ImportReport, ImportStatus};
+use protocol::customer_ledger::{self, CustomerEntry, CustomerLedger, CustomerQuery};
+use tracing::info;
+
+use crate::ReceiverState;
+
+/// Registers every customer in a CSV or JSONL import as a receiver. Any
+/// `receiver_client_id` column is ignored, since receivers register handles for themselves.
+/// Registered rows are recorded in the ledger.
+pub async fn import_customers(customers: &CustomerLedger, input: &str, csv: bool) -> ImportReport {
+  let (client_id, client_secret) = util::get_client_id_and_client_secret();
+  let receiver_secret = crate::r_config::get_webhook_secret();
+
+  let rows = customer_import::parse_rows(input, csv)
+    .into_iter()
+    .map(|(line, row)| {
+      let row = row.map(|mut row| {
+        row.receiver_client_id = None;
+        row
+      });
+      (line, row)
+    })
+    .collect();
+
+  let report =
+    customer_import::register_all(rows, crate::r_config::get_import_concurrency(), |_| {
+      Ok(
+        versa::client::VersaClient::new(client_id.clone(), client_secret.clone())
+          .with_client_string(&util::get_client_string())
+          .receiving_client(receiver_secret.clone()),
+      )
+    })
+    .await;
+
+  for result in &report.results {
+    if let (ImportStatus::Registered, Some(row)) = (&result.status, &result.row) {
+      if let Err(e) = customers.record_registered(&row.handle, &row.handle_type, None) {
+        info!("Failed to record customer in ledger: {:?}", e);
+      }
+    }
+  }
+
+  report
+}
+
+pub async fn import(
+  State(state): State<ReceiverState>,
+  headers: HeaderMap,
+  body: String,
+) -> Response {
+  let csv = customer_import::is_csv_content_type(
+    headers
+      .get(http::header::CONTENT_TYPE)
+      .and_then(|val| val.to_str().ok()),
+  );
+  let report = import_customers(&state.customers, &body, csv).await;
+  let status = if report.failed > 0 {
+    http::StatusCode::MULTI_STATUS
+  } else {
+    http::StatusCode::OK
+  };
+  (status, axum::Json(report)).into_response()
+}
+
+pub async fn list(
+  State(state): State<ReceiverState>,
+  Query(query): Query<CustomerQuery>,
+) -> Result<axum::Json<Vec<CustomerEntry>>, (axum::http::StatusCode, String)> {
+  customer_ledger::list_customers(&state.customers, &query).map(axum::Json)
+}
+
+/// Every ledger entry for the handle, including deregistrations
+pub async fn lookup(
+  State(state): State<ReceiverState>,
+  Path(handle): Path<String>,
+) -> Result<axum::Json<Vec<CustomerEntry>>, (axum::http::StatusCode, String)> {
+  customer_ledger::lookup_customer(&state.customers, &handle).map(axum::Json)
+}
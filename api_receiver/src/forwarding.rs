@@ -0,0 +1,532 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::{Path, State};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use tracing::info;
+
+use crate::routes::DecryptedPayload;
+use crate::sinks::{ReceiptSink, ReceiptSinks};
+use crate::ReceiverState;
+
+const WORKER_BATCH_SIZE: usize = 50;
+
+/// Durable record of every pending (receipt, sink) forward. Decrypted payloads are written
+/// before the first attempt, since the receipt's key cannot be checked out again, so that
+/// failed forwards can be retried by the worker independently of the inbound webhook.
+/// Rows are deleted once delivered, and forwards that exhaust their attempts are moved to
+/// the `forwarding_dead_letters` table.
+///
+/// Each attempt holds a lease on its row, so the worker never picks up a forward that is
+/// still being attempted.
+pub struct ForwardingQueue {
+  conn: Mutex<Connection>,
+  max_attempts: u32,
+  retry_base_secs: i64,
+  lease_secs: i64,
+}
+
+pub struct Forward {
+  pub id: i64,
+  pub receipt_id: String,
+  pub sink: String,
+  pub payload: String,
+  pub attempts: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeadLetter {
+  pub id: i64,
+  pub receipt_id: String,
+  pub sink: String,
+  pub attempts: u32,
+  pub last_error: Option<String>,
+  pub created_at: i64,
+  pub failed_at: i64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FailureOutcome {
+  Retry { next_attempt_at: i64 },
+  DeadLettered,
+}
+
+impl ForwardingQueue {
+  pub fn open(
+    path: &str,
+    max_attempts: u32,
+    retry_base_secs: i64,
+    lease_secs: i64,
+  ) -> rusqlite::Result<Self> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(
+      "CREATE TABLE IF NOT EXISTS forwarding (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        receipt_id TEXT NOT NULL,
+        sink TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at INTEGER NOT NULL,
+        last_error TEXT,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+      );
+      CREATE INDEX IF NOT EXISTS forwarding_due ON forwarding (next_attempt_at);
+      CREATE TABLE IF NOT EXISTS forwarding_dead_letters (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        receipt_id TEXT NOT NULL,
+        sink TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        attempts INTEGER NOT NULL,
+        last_error TEXT,
+        created_at INTEGER NOT NULL,
+        failed_at INTEGER NOT NULL
+      );",
+    )?;
+    Ok(Self {
+      conn: Mutex::new(conn),
+      max_attempts,
+      retry_base_secs,
+      lease_secs,
+    })
+  }
+
+  /// Persists a forward ahead of its first attempt, leased to the caller so that the
+  /// worker leaves it alone while the caller makes that attempt.
+  pub fn enqueue(&self, receipt_id: &str, sink: &str, payload: &str) -> rusqlite::Result<i64> {
+    let now = util::unix_timestamp();
+    let conn = self.conn.lock().unwrap();
+    conn.execute(
+      "INSERT INTO forwarding (receipt_id, sink, payload, next_attempt_at, created_at, updated_at)
+      VALUES (?1, ?2, ?3, ?4, ?5, ?5)",
+      params![receipt_id, sink, payload, now + self.lease_secs, now],
+    )?;
+    Ok(conn.last_insert_rowid())
+  }
+
+  /// Removes a delivered row, so that the decrypted receipt does not outlive its delivery
+  pub fn mark_delivered(&self, id: i64) -> rusqlite::Result<()> {
+    let conn = self.conn.lock().unwrap();
+    conn.execute("DELETE FROM forwarding WHERE id = ?1", params![id])?;
+    Ok(())
+  }
+
+  /// Records a failed attempt, scheduling the next one with exponential backoff or moving
+  /// the forward to the dead-letter table once `max_attempts` is reached.
+  pub fn record_failure(&self, id: i64, error: &str) -> rusqlite::Result<FailureOutcome> {
+    let now = util::unix_timestamp();
+    let mut conn = self.conn.lock().unwrap();
+    let tx = conn.transaction()?;
+
+    let attempts: u32 = tx.query_row(
+      "SELECT attempts FROM forwarding WHERE id = ?1",
+      params![id],
+      |row| row.get(0),
+    )?;
+    let attempts = attempts + 1;
+
+    let outcome = if attempts >= self.max_attempts {
+      tx.execute(
+        "INSERT INTO forwarding_dead_letters (receipt_id, sink, payload, attempts, last_error,
+          created_at, failed_at)
+        SELECT receipt_id, sink, payload, ?2, ?3, created_at, ?4
+        FROM forwarding WHERE id = ?1",
+        params![id, attempts, error, now],
+      )?;
+      tx.execute("DELETE FROM forwarding WHERE id = ?1", params![id])?;
+      FailureOutcome::DeadLettered
+    } else {
+      let next_attempt_at = now + util::retry_delay(self.retry_base_secs, attempts);
+      tx.execute(
+        "UPDATE forwarding SET attempts = ?2, last_error = ?3, next_attempt_at = ?4,
+          updated_at = ?5
+        WHERE id = ?1",
+        params![id, attempts, error, next_attempt_at, now],
+      )?;
+      FailureOutcome::Retry { next_attempt_at }
+    };
+
+    tx.commit()?;
+    Ok(outcome)
+  }
+
+  /// Leases up to `limit` forwards whose backoff has elapsed, or whose last attempt
+  /// outlived its lease
+  pub fn claim_due(&self, now: i64, limit: usize) -> rusqlite::Result<Vec<Forward>> {
+    let mut conn = self.conn.lock().unwrap();
+    let tx = conn.transaction()?;
+
+    let forwards = {
+      let mut stmt = tx.prepare(
+        "SELECT id, receipt_id, sink, payload, attempts
+        FROM forwarding WHERE next_attempt_at <= ?1
+        ORDER BY next_attempt_at LIMIT ?2",
+      )?;
+      let rows = stmt.query_map(params![now, limit as i64], |row| {
+        Ok(Forward {
+          id: row.get(0)?,
+          receipt_id: row.get(1)?,
+          sink: row.get(2)?,
+          payload: row.get(3)?,
+          attempts: row.get(4)?,
+        })
+      })?;
+      rows.collect::<rusqlite::Result<Vec<_>>>()?
+    };
+
+    for forward in &forwards {
+      tx.execute(
+        "UPDATE forwarding SET next_attempt_at = ?2, updated_at = ?3 WHERE id = ?1",
+        params![forward.id, now + self.lease_secs, now],
+      )?;
+    }
+
+    tx.commit()?;
+    Ok(forwards)
+  }
+
+  pub fn dead_letters(&self) -> rusqlite::Result<Vec<DeadLetter>> {
+    let conn = self.conn.lock().unwrap();
+    let mut stmt = conn.prepare(
+      "SELECT id, receipt_id, sink, attempts, last_error, created_at, failed_at
+      FROM forwarding_dead_letters ORDER BY failed_at DESC",
+    )?;
+    let rows = stmt.query_map([], |row| {
+      Ok(DeadLetter {
+        id: row.get(0)?,
+        receipt_id: row.get(1)?,
+        sink: row.get(2)?,
+        attempts: row.get(3)?,
+        last_error: row.get(4)?,
+        created_at: row.get(5)?,
+        failed_at: row.get(6)?,
+      })
+    })?;
+    rows.collect()
+  }
+
+  /// Moves a dead-lettered forward back into the queue with a fresh attempt budget, due
+  /// immediately. Returns false if no dead letter exists with the given id.
+  pub fn replay_dead_letter(&self, id: i64) -> rusqlite::Result<bool> {
+    let now = util::unix_timestamp();
+    let mut conn = self.conn.lock().unwrap();
+    let tx = conn.transaction()?;
+
+    let exists = tx
+      .query_row(
+        "SELECT id FROM forwarding_dead_letters WHERE id = ?1",
+        params![id],
+        |row| row.get::<_, i64>(0),
+      )
+      .optional()?
+      .is_some();
+    if !exists {
+      return Ok(false);
+    }
+
+    tx.execute(
+      "INSERT INTO forwarding (receipt_id, sink, payload, last_error, next_attempt_at,
+        created_at, updated_at)
+      SELECT receipt_id, sink, payload, last_error, ?2, created_at, ?2
+      FROM forwarding_dead_letters WHERE id = ?1",
+      params![id, now],
+    )?;
+    tx.execute(
+      "DELETE FROM forwarding_dead_letters WHERE id = ?1",
+      params![id],
+    )?;
+    tx.commit()?;
+    Ok(true)
+  }
+}
+
+/// Persists a forward of the payload to every sink, then makes the first attempt at each.
+/// Returns whether every sink accepted the payload; failed forwards stay queued for retry.
+pub async fn forward(
+  queue: &ForwardingQueue,
+  sinks: &ReceiptSinks,
+  payload: &DecryptedPayload,
+) -> rusqlite::Result<bool> {
+  let body = serde_json::to_string(payload)
+    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+  let mut forwards = Vec::new();
+  for sink in sinks.iter() {
+    let id = queue.enqueue(&payload.receipt_id, sink.name(), &body)?;
+    forwards.push((id, sink));
+  }
+
+  let attempts = forwards
+    .into_iter()
+    .map(|(id, sink)| attempt(queue, id, sink.as_ref(), payload));
+  let delivered = futures::future::join_all(attempts).await;
+  Ok(delivered.into_iter().all(|delivered| delivered))
+}
+
+async fn attempt(
+  queue: &ForwardingQueue,
+  id: i64,
+  sink: &dyn ReceiptSink,
+  payload: &DecryptedPayload,
+) -> bool {
+  match sink.deliver(payload).await {
+    Ok(()) => {
+      info!(
+        "Delivered receipt {} to {} sink",
+        payload.receipt_id,
+        sink.name()
+      );
+      if let Err(e) = queue.mark_delivered(id) {
+        info!("Failed to record forward in queue: {:?}", e);
+      }
+      true
+    }
+    Err(e) => {
+      info!(
+        "Failed to deliver receipt {} to {} sink: {}",
+        payload.receipt_id,
+        sink.name(),
+        e
+      );
+      record_failure(queue, id, &payload.receipt_id, &e);
+      false
+    }
+  }
+}
+
+fn record_failure(queue: &ForwardingQueue, id: i64, receipt_id: &str, error: &str) {
+  match queue.record_failure(id, error) {
+    Ok(FailureOutcome::DeadLettered) => {
+      info!("Forward of receipt {} moved to dead letters", receipt_id)
+    }
+    Ok(FailureOutcome::Retry { .. }) => {}
+    Err(e) => info!("Failed to record forward outcome in queue: {:?}", e),
+  }
+}
+
+/// Periodically retries forwards whose backoff or lease has elapsed.
+pub async fn run_worker(queue: Arc<ForwardingQueue>, sinks: Arc<ReceiptSinks>) {
+  let poll_interval = Duration::from_secs(crate::r_config::get_forwarding_poll_secs());
+  loop {
+    tokio::time::sleep(poll_interval).await;
+    retry_due(&queue, &sinks, util::unix_timestamp()).await;
+  }
+}
+
+async fn retry_due(queue: &ForwardingQueue, sinks: &ReceiptSinks, now: i64) {
+  let due = match queue.claim_due(now, WORKER_BATCH_SIZE) {
+    Ok(val) => val,
+    Err(e) => {
+      info!("Failed to read due forwards from queue: {:?}", e);
+      return;
+    }
+  };
+
+  for forward in due {
+    info!(
+      "Retrying delivery of receipt {} to {} sink (attempt {})",
+      forward.receipt_id,
+      forward.sink,
+      forward.attempts + 1
+    );
+    let Some(sink) = sinks.get(&forward.sink) else {
+      let error = format!("The {} sink is not configured", forward.sink);
+      record_failure(queue, forward.id, &forward.receipt_id, &error);
+      continue;
+    };
+    match serde_json::from_str::<DecryptedPayload>(&forward.payload) {
+      Ok(payload) => {
+        attempt(queue, forward.id, sink.as_ref(), &payload).await;
+      }
+      Err(e) => {
+        let error = format!("Failed to restore persisted payload: {}", e);
+        record_failure(queue, forward.id, &forward.receipt_id, &error);
+      }
+    }
+  }
+}
+
+pub async fn dead_letters(
+  State(state): State<ReceiverState>,
+) -> Result<axum::Json<Vec<DeadLetter>>, (axum::http::StatusCode, String)> {
+  let dead_letters = state.forwarding.dead_letters().map_err(|e| {
+    info!("Failed to read dead letters: {:?}", e);
+    (
+      http::StatusCode::INTERNAL_SERVER_ERROR,
+      "Failed to read dead letters".to_string(),
+    )
+  })?;
+  Ok(axum::Json(dead_letters))
+}
+
+pub async fn replay_dead_letter(
+  State(state): State<ReceiverState>,
+  Path(id): Path<i64>,
+) -> Result<axum::http::StatusCode, (axum::http::StatusCode, String)> {
+  match state.forwarding.replay_dead_letter(id) {
+    Ok(true) => {
+      info!("Dead letter {} queued for redelivery", id);
+      Ok(http::StatusCode::ACCEPTED)
+    }
+    Ok(false) => Err((
+      http::StatusCode::NOT_FOUND,
+      format!("No dead letter found with id {}", id),
+    )),
+    Err(e) => {
+      info!("Failed to replay dead letter {}: {:?}", id, e);
+      Err((
+        http::StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to replay dead letter".to_string(),
+      ))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use std::sync::atomic::{AtomicU32, Ordering};
+
+  use super::*;
+  use crate::routes::test_payload;
+  use pretty_assertions::assert_eq;
+
+  /// Fails the first `failures` deliveries, then accepts every payload
+  struct FlakySink {
+    name: &'static str,
+    failures: u32,
+    attempts: Arc<AtomicU32>,
+  }
+
+  #[async_trait::async_trait]
+  impl ReceiptSink for FlakySink {
+    fn name(&self) -> &str {
+      self.name
+    }
+
+    async fn deliver(&self, _payload: &DecryptedPayload) -> Result<(), String> {
+      if self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures {
+        return Err("refused".into());
+      }
+      Ok(())
+    }
+  }
+
+  fn flaky_sink(name: &'static str, failures: u32) -> (FlakySink, Arc<AtomicU32>) {
+    let attempts = Arc::new(AtomicU32::new(0));
+    let sink = FlakySink {
+      name,
+      failures,
+      attempts: attempts.clone(),
+    };
+    (sink, attempts)
+  }
+
+  #[test]
+  fn test_exhausted_forward_should_be_dead_lettered_and_replayable() {
+    let queue = ForwardingQueue::open(":memory:", 2, 30, 60).unwrap();
+    let id = queue.enqueue("rct_123", "http", "{}").unwrap();
+
+    let now = util::unix_timestamp();
+    assert!(queue.claim_due(now, 10).unwrap().is_empty());
+
+    let Ok(FailureOutcome::Retry { .. }) = queue.record_failure(id, "refused") else {
+      panic!("The first failure should be retried");
+    };
+    assert_eq!(
+      queue.record_failure(id, "refused").unwrap(),
+      FailureOutcome::DeadLettered
+    );
+
+    let dead_letters = queue.dead_letters().unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].sink, "http");
+    assert_eq!(dead_letters[0].last_error.as_deref(), Some("refused"));
+
+    assert!(queue.replay_dead_letter(dead_letters[0].id).unwrap());
+    assert!(queue.dead_letters().unwrap().is_empty());
+
+    let due = queue.claim_due(now, 10).unwrap();
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].attempts, 0);
+    assert_eq!(due[0].payload, "{}");
+  }
+
+  #[test]
+  fn test_claimed_forward_should_not_be_claimed_again_until_lease_expires() {
+    let queue = ForwardingQueue::open(":memory:", 8, 30, 60).unwrap();
+    queue.enqueue("rct_123", "http", "{}").unwrap();
+
+    // The caller holds the lease while it makes the first attempt
+    let now = util::unix_timestamp();
+    assert!(queue.claim_due(now, 10).unwrap().is_empty());
+
+    // An attempt that never reported back is picked up once its lease expires, once
+    assert_eq!(queue.claim_due(now + 60, 10).unwrap().len(), 1);
+    assert!(queue.claim_due(now + 60, 10).unwrap().is_empty());
+    assert_eq!(queue.claim_due(now + 120, 10).unwrap().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_forward_should_delete_delivered_and_keep_failed() {
+    let queue = ForwardingQueue::open(":memory:", 8, 30, 60).unwrap();
+    let (healthy, _) = flaky_sink("healthy", 0);
+    let (failing, _) = flaky_sink("failing", 1);
+    let sinks = ReceiptSinks::default()
+      .with_sink(healthy)
+      .with_sink(failing);
+
+    assert!(!forward(&queue, &sinks, &test_payload("rct_1"))
+      .await
+      .unwrap());
+
+    let queued = queue.claim_due(util::unix_timestamp() + 3600, 10).unwrap();
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].sink, "failing");
+    assert_eq!(queued[0].attempts, 1);
+    let payload: DecryptedPayload = serde_json::from_str(&queued[0].payload).unwrap();
+    assert_eq!(payload.receipt_id, "rct_1");
+  }
+
+  #[tokio::test]
+  async fn test_worker_should_redeliver_failed_forward() {
+    let queue = ForwardingQueue::open(":memory:", 8, 30, 60).unwrap();
+    let (sink, attempts) = flaky_sink("flaky", 1);
+    let sinks = ReceiptSinks::default().with_sink(sink);
+
+    assert!(!forward(&queue, &sinks, &test_payload("rct_1"))
+      .await
+      .unwrap());
+
+    // Not due until the backoff has elapsed
+    let now = util::unix_timestamp();
+    retry_due(&queue, &sinks, now).await;
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+    retry_due(&queue, &sinks, now + 30).await;
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    assert!(queue.claim_due(now + 3600, 10).unwrap().is_empty());
+    assert!(queue.dead_letters().unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_worker_should_dead_letter_forward_to_unconfigured_sink() {
+    let queue = ForwardingQueue::open(":memory:", 1, 30, 60).unwrap();
+    let payload = serde_json::to_string(&test_payload("rct_1")).unwrap();
+    queue.enqueue("rct_1", "removed", &payload).unwrap();
+
+    retry_due(
+      &queue,
+      &ReceiptSinks::default(),
+      util::unix_timestamp() + 60,
+    )
+    .await;
+
+    let dead_letters = queue.dead_letters().unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(
+      dead_letters[0].last_error.as_deref(),
+      Some("The removed sink is not configured")
+    );
+  }
+}
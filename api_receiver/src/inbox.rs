@@ -0,0 +1,423 @@
+use std::sync::Mutex;
+
+use axum::extract::{Path, Query, State};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::info;
+
+use crate::routes::DecryptedPayload;
+use crate::ReceiverState;
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+
+/// Every receipt and itinerary received, kept after it has been forwarded. Handles are
+/// stored as one row per handle so that receipts can be looked up by any of them.
+pub struct Inbox {
+  conn: Mutex<Connection>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InboxEntry {
+  pub receipt_id: String,
+  pub event: String,
+  pub transaction_id: String,
+  pub sender_client_id: String,
+  pub schema_version: String,
+  pub received_at: i64,
+  pub payload: Value,
+}
+
+/// Query parameters for listing received receipts, newest first
+#[derive(Debug, Default, Deserialize)]
+pub struct InboxQuery {
+  pub event: Option<String>,
+  pub transaction_id: Option<String>,
+  pub sender_client_id: Option<String>,
+  /// Matches receipts with this value for any of their handles
+  pub handle: Option<String>,
+  pub received_after: Option<i64>,
+  pub received_before: Option<i64>,
+  pub limit: Option<u32>,
+  /// The `next_cursor` of the previous page
+  pub cursor: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InboxPage {
+  pub receipts: Vec<InboxEntry>,
+  /// Pass as `cursor` to fetch the next page, absent on the last page
+  pub next_cursor: Option<i64>,
+}
+
+/// Flattens the handles object into (handle_type, handle) pairs, including each value of
+/// handles that hold a list
+fn flatten_handles(handles: &Value) -> Vec<(String, String)> {
+  let Some(handles) = handles.as_object() else {
+    return Vec::new();
+  };
+  handles
+    .iter()
+    .flat_map(|(handle_type, value)| {
+      let values = match value {
+        Value::Array(values) => values.iter().collect(),
+        value => vec![value],
+      };
+      values
+        .into_iter()
+        .filter_map(Value::as_str)
+        .map(|handle| (handle_type.clone(), handle.to_string()))
+        .collect::<Vec<_>>()
+    })
+    .collect()
+}
+
+fn entry_from_row(row: &Row) -> rusqlite::Result<InboxEntry> {
+  Ok(InboxEntry {
+    receipt_id: row.get(1)?,
+    event: row.get(2)?,
+    transaction_id: row.get(3)?,
+    sender_client_id: row.get(4)?,
+    schema_version: row.get(5)?,
+    received_at: row.get(6)?,
+    payload: serde_json::from_str(&row.get::<_, String>(7)?).unwrap_or(Value::Null),
+  })
+}
+
+impl Inbox {
+  pub fn open(path: &str) -> rusqlite::Result<Self> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(
+      "CREATE TABLE IF NOT EXISTS receipts (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        receipt_id TEXT NOT NULL UNIQUE,
+        event TEXT NOT NULL,
+        transaction_id TEXT NOT NULL,
+        sender_client_id TEXT NOT NULL,
+        schema_version TEXT NOT NULL,
+        received_at INTEGER NOT NULL,
+        payload TEXT NOT NULL
+      );
+      CREATE INDEX IF NOT EXISTS receipts_transaction_id ON receipts (transaction_id);
+      CREATE INDEX IF NOT EXISTS receipts_sender ON receipts (sender_client_id);
+      CREATE INDEX IF NOT EXISTS receipts_received_at ON receipts (received_at);
+      CREATE TABLE IF NOT EXISTS receipt_handles (
+        receipt_id TEXT NOT NULL,
+        handle_type TEXT NOT NULL,
+        handle TEXT NOT NULL,
+        PRIMARY KEY (receipt_id, handle_type, handle)
+      );
+      CREATE INDEX IF NOT EXISTS receipt_handles_handle ON receipt_handles (handle);",
+    )?;
+    Ok(Self {
+      conn: Mutex::new(conn),
+    })
+  }
+
+  /// Records a received payload, updating any earlier copy with the same receipt_id in
+  /// place so that it keeps its position in the inbox and its `received_at`
+  pub fn record(&self, event: &str, payload: &DecryptedPayload) -> rusqlite::Result<()> {
+    let handles = serde_json::to_value(&payload.handles).unwrap_or(Value::Null);
+    let body = serde_json::to_string(payload)
+      .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    let mut conn = self.conn.lock().unwrap();
+    let tx = conn.transaction()?;
+
+    tx.execute(
+      "INSERT INTO receipts (receipt_id, event, transaction_id, sender_client_id,
+        schema_version, received_at, payload)
+      VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+      ON CONFLICT (receipt_id) DO UPDATE SET event = excluded.event,
+        transaction_id = excluded.transaction_id, sender_client_id = excluded.sender_client_id,
+        schema_version = excluded.schema_version, payload = excluded.payload",
+      params![
+        payload.receipt_id,
+        event,
+        payload.transaction_id,
+        payload.sender_client_id,
+        payload.schema_version,
+        util::unix_timestamp(),
+        body,
+      ],
+    )?;
+    tx.execute(
+      "DELETE FROM receipt_handles WHERE receipt_id = ?1",
+      params![payload.receipt_id],
+    )?;
+    for (handle_type, handle) in flatten_handles(&handles) {
+      tx.execute(
+        "INSERT OR IGNORE INTO receipt_handles (receipt_id, handle_type, handle)
+        VALUES (?1, ?2, ?3)",
+        params![payload.receipt_id, handle_type, handle],
+      )?;
+    }
+
+    tx.commit()
+  }
+
+  pub fn get(&self, receipt_id: &str) -> rusqlite::Result<Option<InboxEntry>> {
+    let conn = self.conn.lock().unwrap();
+    conn
+      .query_row(
+        "SELECT id, receipt_id, event, transaction_id, sender_client_id, schema_version,
+          received_at, payload
+        FROM receipts WHERE receipt_id = ?1",
+        params![receipt_id],
+        entry_from_row,
+      )
+      .optional()
+  }
+
+  /// Lists matching receipts newest first, one page at a time. The cursor is the internal
+  /// row id of the last receipt of the previous page.
+  pub fn list(&self, query: &InboxQuery) -> rusqlite::Result<InboxPage> {
+    let limit = query
+      .limit
+      .unwrap_or(DEFAULT_PAGE_SIZE)
+      .clamp(1, MAX_PAGE_SIZE);
+    let conn = self.conn.lock().unwrap();
+    let mut stmt = conn.prepare(
+      "SELECT id, receipt_id, event, transaction_id, sender_client_id, schema_version,
+        received_at, payload
+      FROM receipts
+      WHERE (?1 IS NULL OR event = ?1)
+        AND (?2 IS NULL OR transaction_id = ?2)
+        AND (?3 IS NULL OR sender_client_id = ?3)
+        AND (?4 IS NULL OR receipt_id IN
+          (SELECT receipt_id FROM receipt_handles WHERE handle = ?4))
+        AND (?5 IS NULL OR received_at >= ?5)
+        AND (?6 IS NULL OR received_at < ?6)
+        AND (?7 IS NULL OR id < ?7)
+      ORDER BY id DESC LIMIT ?8",
+    )?;
+
+    // Fetch one extra row to know whether there is a next page
+    let rows = stmt.query_map(
+      params![
+        query.event,
+        query.transaction_id,
+        query.sender_client_id,
+        query.handle,
+        query.received_after,
+        query.received_before,
+        query.cursor,
+        limit + 1,
+      ],
+      |row| Ok((row.get::<_, i64>(0)?, entry_from_row(row)?)),
+    )?;
+    let mut rows = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let next_cursor = if rows.len() > limit as usize {
+      rows.truncate(limit as usize);
+      rows.last().map(|(id, _)| *id)
+    } else {
+      None
+    };
+    Ok(InboxPage {
+      receipts: rows.into_iter().map(|(_, entry)| entry).collect(),
+      next_cursor,
+    })
+  }
+}
+
+fn store_error(e: rusqlite::Error) -> (axum::http::StatusCode, String) {
+  info!("Failed to read receipt inbox: {:?}", e);
+  (
+    http::StatusCode::INTERNAL_SERVER_ERROR,
+    "Failed to read receipt inbox".to_string(),
+  )
+}
+
+pub async fn list(
+  State(state): State<ReceiverState>,
+  Query(query): Query<InboxQuery>,
+) -> Result<axum::Json<InboxPage>, (axum::http::StatusCode, String)> {
+  state
+    .inbox
+    .list(&query)
+    .map(axum::Json)
+    .map_err(store_error)
+}
+
+pub async fn get(
+  State(state): State<ReceiverState>,
+  Path(receipt_id): Path<String>,
+) -> Result<axum::Json<InboxEntry>, (axum::http::StatusCode, String)> {
+  match state.inbox.get(&receipt_id).map_err(store_error)? {
+    Some(entry) => Ok(axum::Json(entry)),
+    None => Err((
+      http::StatusCode::NOT_FOUND,
+      format!("No received receipt with id {}", receipt_id),
+    )),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::routes::test_payload;
+  use pretty_assertions::assert_eq;
+  use versa::protocol::TransactionHandles;
+
+  #[test]
+  fn test_handles_should_flatten_to_pairs() {
+    let handles = serde_json::json!({
+      "customer_email": "jane@example.com",
+      "customer_email_domain": null,
+      "merchant_group_codes": ["a", "b"]
+    });
+    let mut pairs = flatten_handles(&handles);
+    pairs.sort();
+    assert_eq!(
+      pairs,
+      vec![
+        ("customer_email".into(), "jane@example.com".into()),
+        ("merchant_group_codes".into(), "a".into()),
+        ("merchant_group_codes".into(), "b".into()),
+      ]
+    );
+  }
+
+  fn record(inbox: &Inbox, event: &str, receipt_id: &str, transaction_id: &str, email: &str) {
+    let mut payload = test_payload(receipt_id);
+    payload.transaction_id = transaction_id.into();
+    payload.handles = TransactionHandles::new().with_customer_email(email.into());
+    inbox.record(event, &payload).unwrap();
+  }
+
+  fn receipt_ids(page: &InboxPage) -> Vec<&str> {
+    page
+      .receipts
+      .iter()
+      .map(|entry| entry.receipt_id.as_str())
+      .collect()
+  }
+
+  #[test]
+  fn test_list_should_filter_receipts() {
+    let inbox = Inbox::open(":memory:").unwrap();
+    record(&inbox, "receipt", "rct_1", "txn_1", "jane@example.com");
+    record(&inbox, "itinerary", "rct_2", "txn_2", "john@example.com");
+    record(&inbox, "receipt", "rct_3", "txn_3", "jane@example.com");
+
+    let list = |query: InboxQuery| inbox.list(&query).unwrap();
+    assert_eq!(
+      receipt_ids(&list(InboxQuery {
+        event: Some("receipt".into()),
+        ..Default::default()
+      })),
+      vec!["rct_3", "rct_1"]
+    );
+    assert_eq!(
+      receipt_ids(&list(InboxQuery {
+        transaction_id: Some("txn_2".into()),
+        ..Default::default()
+      })),
+      vec!["rct_2"]
+    );
+    assert_eq!(
+      receipt_ids(&list(InboxQuery {
+        handle: Some("jane@example.com".into()),
+        ..Default::default()
+      })),
+      vec!["rct_3", "rct_1"]
+    );
+    assert_eq!(
+      receipt_ids(&list(InboxQuery {
+        sender_client_id: Some("versa_cid_other".into()),
+        ..Default::default()
+      })),
+      Vec::<&str>::new()
+    );
+
+    let now = util::unix_timestamp();
+    assert_eq!(
+      list(InboxQuery {
+        received_after: Some(now - 60),
+        received_before: Some(now + 60),
+        ..Default::default()
+      })
+      .receipts
+      .len(),
+      3
+    );
+    assert!(list(InboxQuery {
+      received_after: Some(now + 60),
+      ..Default::default()
+    })
+    .receipts
+    .is_empty());
+  }
+
+  #[test]
+  fn test_list_should_page_with_cursor() {
+    let inbox = Inbox::open(":memory:").unwrap();
+    for i in 1..=5 {
+      record(
+        &inbox,
+        "receipt",
+        &format!("rct_{}", i),
+        "txn_1",
+        "jane@example.com",
+      );
+    }
+
+    let mut query = InboxQuery {
+      limit: Some(2),
+      ..Default::default()
+    };
+    let mut pages = Vec::new();
+    loop {
+      let page = inbox.list(&query).unwrap();
+      pages.push(
+        receipt_ids(&page)
+          .into_iter()
+          .map(String::from)
+          .collect::<Vec<_>>(),
+      );
+      match page.next_cursor {
+        Some(cursor) => query.cursor = Some(cursor),
+        None => break,
+      }
+    }
+    assert_eq!(
+      pages,
+      vec![
+        vec!["rct_5".to_string(), "rct_4".to_string()],
+        vec!["rct_3".to_string(), "rct_2".to_string()],
+        vec!["rct_1".to_string()],
+      ]
+    );
+  }
+
+  #[test]
+  fn test_rerecorded_receipt_should_keep_its_position_and_refresh_handles() {
+    let inbox = Inbox::open(":memory:").unwrap();
+    record(&inbox, "receipt", "rct_1", "txn_1", "jane@example.com");
+    record(&inbox, "receipt", "rct_2", "txn_2", "jane@example.com");
+    let received_at = inbox.get("rct_1").unwrap().unwrap().received_at;
+
+    record(&inbox, "receipt", "rct_1", "txn_1b", "janet@example.com");
+
+    let entry = inbox.get("rct_1").unwrap().unwrap();
+    assert_eq!(entry.transaction_id, "txn_1b");
+    assert_eq!(entry.received_at, received_at);
+    let page = inbox.list(&InboxQuery::default()).unwrap();
+    assert_eq!(receipt_ids(&page), vec!["rct_2", "rct_1"]);
+
+    let by_handle = |handle: &str| {
+      let query = InboxQuery {
+        handle: Some(handle.into()),
+        ..Default::default()
+      };
+      receipt_ids(&inbox.list(&query).unwrap())
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(by_handle("jane@example.com"), vec!["rct_2".to_string()]);
+    assert_eq!(by_handle("janet@example.com"), vec!["rct_1".to_string()]);
+  }
+}
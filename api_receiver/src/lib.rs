@@ -1,7 +1,16 @@
-use axum::routing::{delete, post};
+use std::sync::Arc;
+
+use axum::extract::DefaultBodyLimit;
+use axum::routing::{delete, get, post};
 use axum::Router;
 
+pub mod customers;
+pub mod forwarding;
+pub mod inbox;
+pub mod quarantine;
+pub mod replay;
 pub mod routes;
+pub mod sinks;
 
 mod hmac_verify;
 mod model;
@@ -10,9 +19,124 @@ mod report_misuse; // move to SDK
 
 mod schema; // move to SDK
 
+#[derive(Clone)]
+pub struct ReceiverState {
+  pub customers: Arc<protocol::customer_ledger::CustomerLedger>,
+  pub quarantine: Arc<quarantine::Quarantine>,
+  pub validators: Arc<protocol::schema::ValidatorCache>,
+  pub sinks: Arc<sinks::ReceiptSinks>,
+  pub forwarding: Arc<forwarding::ForwardingQueue>,
+  pub inbox: Arc<inbox::Inbox>,
+  pub processed: Arc<replay::ProcessedReceipts>,
+}
+
+/// Opens the ledger of customers registered by this receiver
+pub fn open_customer_ledger() -> protocol::customer_ledger::CustomerLedger {
+  protocol::customer_ledger::CustomerLedger::open(&r_config::get_customer_ledger_path())
+    .expect("Failed to open customer ledger")
+}
+
+/// State backed by in-memory stores, for tests of the route handlers
+#[cfg(test)]
+pub(crate) fn test_state(sinks: sinks::ReceiptSinks) -> ReceiverState {
+  ReceiverState {
+    customers: Arc::new(protocol::customer_ledger::CustomerLedger::open(":memory:").unwrap()),
+    quarantine: Arc::new(quarantine::Quarantine::open(":memory:").unwrap()),
+    validators: Arc::new(protocol::schema::ValidatorCache::default()),
+    sinks: Arc::new(sinks),
+    forwarding: Arc::new(forwarding::ForwardingQueue::open(":memory:", 2, 30, 60).unwrap()),
+    inbox: Arc::new(inbox::Inbox::open(":memory:").unwrap()),
+    processed: Arc::new(replay::ProcessedReceipts::open(":memory:", 86_400, 120).unwrap()),
+  }
+}
+
+/// Must be called from within a Tokio runtime, see [`configure_with_sinks`]
 pub fn configure() -> Router {
+  configure_with_sinks(sinks::ReceiptSinks::from_config())
+}
+
+/// Same as [`configure`], delivering received receipts to the given sinks instead of those
+/// named in `VERSA_RECEIPT_SINKS`. Must be called from within a Tokio runtime, which runs
+/// the forwarding retry worker.
+pub fn configure_with_sinks(sinks: sinks::ReceiptSinks) -> Router {
+  // Fail at startup rather than on the first receipt if the policy is invalid
+  r_config::get_validation_policy();
+
+  if let Some(forwarding_secret) = r_config::get_forwarding_secret() {
+    if forwarding_secret == r_config::get_webhook_secret() {
+      panic!("VERSA_FORWARDING_SECRET must differ from VERSA_WEBHOOK_SECRET");
+    }
+  }
+
+  // A sink must time out before its lease lets the worker deliver the receipt again
+  if r_config::get_sink_timeout_secs() as i64 >= r_config::get_forwarding_lease_secs() {
+    panic!("VERSA_SINK_TIMEOUT_SECS must be shorter than VERSA_FORWARDING_LEASE_SECS");
+  }
+
+  let quarantine = quarantine::Quarantine::open(&r_config::get_quarantine_path())
+    .expect("Failed to open receipt quarantine");
+
+  let inbox =
+    inbox::Inbox::open(&r_config::get_inbox_path()).expect("Failed to open receipt inbox");
+
+  let processed = replay::ProcessedReceipts::open(
+    &r_config::get_processed_receipts_path(),
+    r_config::get_processed_receipt_retention_secs(),
+    r_config::get_processed_receipt_lease_secs(),
+  )
+  .expect("Failed to open processed receipts");
+
+  let forwarding = forwarding::ForwardingQueue::open(
+    &r_config::get_forwarding_path(),
+    r_config::get_forwarding_max_attempts(),
+    r_config::get_forwarding_retry_base_secs(),
+    r_config::get_forwarding_lease_secs(),
+  )
+  .expect("Failed to open forwarding queue");
+  let forwarding = Arc::new(forwarding);
+  let sinks = Arc::new(sinks);
+  tokio::spawn(forwarding::run_worker(forwarding.clone(), sinks.clone()));
+
+  // Compile bundled schemas up front so the first receipts are not slowed down
+  let validators = protocol::schema::ValidatorCache::default();
+  validators.warm_up();
+
+  let state = ReceiverState {
+    customers: Arc::new(open_customer_ledger()),
+    quarantine: Arc::new(quarantine),
+    validators: Arc::new(validators),
+    sinks,
+    forwarding,
+    inbox: Arc::new(inbox),
+    processed: Arc::new(processed),
+  };
+
   Router::new()
     .route("/customer", delete(routes::deregister_customer))
+    .route("/customer", get(customers::list))
     .route("/customer", post(routes::register_customer))
+    .route(
+      "/customer/import",
+      post(customers::import).layer(DefaultBodyLimit::max(r_config::get_max_import_bytes())),
+    )
+    .route("/customer/{handle}", get(customers::lookup))
+    .route("/forwarding/dead_letters", get(forwarding::dead_letters))
+    .route(
+      "/forwarding/dead_letters/{id}/replay",
+      post(forwarding::replay_dead_letter),
+    )
+    .route("/metrics/validation", get(schema::metrics))
+    .route("/quarantine", get(quarantine::list))
+    .route(
+      "/quarantine/{receipt_id}",
+      get(quarantine::get).delete(quarantine::discard),
+    )
+    .route(
+      "/quarantine/{receipt_id}/release",
+      post(quarantine::release),
+    )
+    .route("/receipts", get(inbox::list))
+    .route("/receipts/{receipt_id}", get(inbox::get))
     .route("/target", post(routes::target))
+    .with_state(state)
 }
@@ -0,0 +1,309 @@
+use std::sync::Mutex;
+
+use axum::extract::{Path, State};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::info;
+
+use crate::routes::DecryptedPayload;
+use crate::ReceiverState;
+
+/// What to do with a receipt that fails schema validation or consistency checks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValidationPolicy {
+  /// Respond with 422 and do not forward the receipt
+  Reject,
+  /// Forward the receipt with a `validation_failure` annotation
+  Warn,
+  /// Hold the annotated receipt for review, forwarding it only once released
+  Quarantine,
+}
+
+impl std::str::FromStr for ValidationPolicy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "reject" => Ok(ValidationPolicy::Reject),
+      "warn" => Ok(ValidationPolicy::Warn),
+      "quarantine" => Ok(ValidationPolicy::Quarantine),
+      _ => Err(format!("Unknown validation policy: {}", s)),
+    }
+  }
+}
+
+/// Receipts held back from the local target under the quarantine policy
+pub struct Quarantine {
+  conn: Mutex<Connection>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QuarantinedReceipt {
+  pub receipt_id: String,
+  pub sender_client_id: String,
+  pub quarantined_at: i64,
+  pub payload: Value,
+}
+
+impl Quarantine {
+  pub fn open(path: &str) -> rusqlite::Result<Self> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(
+      "CREATE TABLE IF NOT EXISTS quarantine (
+        receipt_id TEXT PRIMARY KEY,
+        sender_client_id TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        quarantined_at INTEGER NOT NULL
+      );",
+    )?;
+    Ok(Self {
+      conn: Mutex::new(conn),
+    })
+  }
+
+  pub fn hold(&self, payload: &DecryptedPayload) -> rusqlite::Result<()> {
+    let conn = self.conn.lock().unwrap();
+    conn.execute(
+      "INSERT OR REPLACE INTO quarantine (receipt_id, sender_client_id, payload, quarantined_at)
+      VALUES (?1, ?2, ?3, ?4)",
+      params![
+        payload.receipt_id,
+        payload.sender_client_id,
+        serde_json::to_string(payload).unwrap(),
+        util::unix_timestamp(),
+      ],
+    )?;
+    Ok(())
+  }
+
+  pub fn list(&self) -> rusqlite::Result<Vec<QuarantinedReceipt>> {
+    let conn = self.conn.lock().unwrap();
+    let mut stmt = conn.prepare(
+      "SELECT receipt_id, sender_client_id, payload, quarantined_at
+      FROM quarantine ORDER BY quarantined_at DESC",
+    )?;
+    let rows = stmt.query_map([], |row| {
+      Ok(QuarantinedReceipt {
+        receipt_id: row.get(0)?,
+        sender_client_id: row.get(1)?,
+        payload: serde_json::from_str(&row.get::<_, String>(2)?).unwrap_or(Value::Null),
+        quarantined_at: row.get(3)?,
+      })
+    })?;
+    rows.collect()
+  }
+
+  pub fn get(&self, receipt_id: &str) -> rusqlite::Result<Option<DecryptedPayload>> {
+    let conn = self.conn.lock().unwrap();
+    let payload = conn
+      .query_row(
+        "SELECT payload FROM quarantine WHERE receipt_id = ?1",
+        params![receipt_id],
+        |row| row.get::<_, String>(0),
+      )
+      .optional()?;
+    Ok(payload.and_then(|payload| serde_json::from_str(&payload).ok()))
+  }
+
+  /// Removes the receipt, returning false if it was not quarantined
+  pub fn remove(&self, receipt_id: &str) -> rusqlite::Result<bool> {
+    let conn = self.conn.lock().unwrap();
+    let removed = conn.execute(
+      "DELETE FROM quarantine WHERE receipt_id = ?1",
+      params![receipt_id],
+    )?;
+    Ok(removed > 0)
+  }
+}
+
+fn store_error(e: rusqlite::Error) -> (axum::http::StatusCode, String) {
+  info!("Failed to access quarantine: {:?}", e);
+  (
+    http::StatusCode::INTERNAL_SERVER_ERROR,
+    "Failed to access quarantine".to_string(),
+  )
+}
+
+fn not_found(receipt_id: &str) -> (axum::http::StatusCode, String) {
+  (
+    http::StatusCode::NOT_FOUND,
+    format!("No quarantined receipt with id {}", receipt_id),
+  )
+}
+
+pub async fn list(
+  State(state): State<ReceiverState>,
+) -> Result<axum::Json<Vec<QuarantinedReceipt>>, (axum::http::StatusCode, String)> {
+  state.quarantine.list().map(axum::Json).map_err(store_error)
+}
+
+pub async fn get(
+  State(state): State<ReceiverState>,
+  Path(receipt_id): Path<String>,
+) -> Result<axum::Json<DecryptedPayload>, (axum::http::StatusCode, String)> {
+  match state.quarantine.get(&receipt_id).map_err(store_error)? {
+    Some(payload) => Ok(axum::Json(payload)),
+    None => Err(not_found(&receipt_id)),
+  }
+}
+
+/// Forwards a quarantined receipt to the receipt sinks, still annotated with its
+/// validation failure, and removes it from quarantine
+pub async fn release(
+  State(state): State<ReceiverState>,
+  Path(receipt_id): Path<String>,
+) -> Result<axum::http::StatusCode, (axum::http::StatusCode, String)> {
+  let Some(payload) = state.quarantine.get(&receipt_id).map_err(store_error)? else {
+    return Err(not_found(&receipt_id));
+  };
+
+  if state.sinks.is_empty() {
+    return Err((
+      http::StatusCode::CONFLICT,
+      "No receipt sinks are configured".to_string(),
+    ));
+  }
+  let delivered = crate::forwarding::forward(&state.forwarding, &state.sinks, &payload)
+    .await
+    .map_err(|e| {
+      info!("Failed to persist receipt for forwarding: {:?}", e);
+      (
+        http::StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to persist receipt for forwarding".to_string(),
+      )
+    })?;
+  state.quarantine.remove(&receipt_id).map_err(store_error)?;
+  info!("Released receipt {} from quarantine", receipt_id);
+
+  // Deliveries that failed are retried from the forwarding queue
+  Ok(if delivered {
+    http::StatusCode::OK
+  } else {
+    http::StatusCode::ACCEPTED
+  })
+}
+
+/// Discards a quarantined receipt without forwarding it
+pub async fn discard(
+  State(state): State<ReceiverState>,
+  Path(receipt_id): Path<String>,
+) -> Result<axum::http::StatusCode, (axum::http::StatusCode, String)> {
+  if !state.quarantine.remove(&receipt_id).map_err(store_error)? {
+    return Err(not_found(&receipt_id));
+  }
+  info!("Discarded receipt {} from quarantine", receipt_id);
+  Ok(http::StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+
+  use std::sync::Arc;
+
+  use super::*;
+  use crate::routes::test_payload;
+  use crate::sinks::{ReceiptSink, ReceiptSinks};
+  use pretty_assertions::assert_eq;
+
+  struct RecordingSink {
+    delivered: Arc<Mutex<Vec<String>>>,
+  }
+
+  #[async_trait::async_trait]
+  impl ReceiptSink for RecordingSink {
+    fn name(&self) -> &str {
+      "recording"
+    }
+
+    async fn deliver(&self, payload: &DecryptedPayload) -> Result<(), String> {
+      self
+        .delivered
+        .lock()
+        .unwrap()
+        .push(payload.receipt_id.clone());
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_held_receipts_should_be_listed_and_removed() {
+    let quarantine = Quarantine::open(":memory:").unwrap();
+    quarantine.hold(&test_payload("rct_1")).unwrap();
+    quarantine.hold(&test_payload("rct_2")).unwrap();
+    // Holding a receipt again replaces it rather than listing it twice
+    quarantine.hold(&test_payload("rct_1")).unwrap();
+
+    let mut held: Vec<String> = quarantine
+      .list()
+      .unwrap()
+      .into_iter()
+      .map(|receipt| receipt.receipt_id)
+      .collect();
+    held.sort();
+    assert_eq!(held, vec!["rct_1".to_string(), "rct_2".to_string()]);
+    assert_eq!(
+      quarantine.get("rct_1").unwrap().unwrap().transaction_id,
+      "txn_123"
+    );
+
+    assert!(quarantine.remove("rct_1").unwrap());
+    assert!(!quarantine.remove("rct_1").unwrap());
+    assert!(quarantine.get("rct_1").unwrap().is_none());
+    assert_eq!(quarantine.list().unwrap().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_released_receipt_should_be_forwarded_and_removed() {
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+    let state = crate::test_state(ReceiptSinks::default().with_sink(RecordingSink {
+      delivered: delivered.clone(),
+    }));
+    state.quarantine.hold(&test_payload("rct_1")).unwrap();
+
+    let status = release(State(state.clone()), Path("rct_1".to_string()))
+      .await
+      .unwrap();
+    assert_eq!(status, http::StatusCode::OK);
+    assert_eq!(*delivered.lock().unwrap(), vec!["rct_1".to_string()]);
+    assert!(state.quarantine.list().unwrap().is_empty());
+
+    let Err((status, _)) = release(State(state), Path("rct_1".to_string())).await else {
+      panic!("A released receipt should no longer be quarantined");
+    };
+    assert_eq!(status, http::StatusCode::NOT_FOUND);
+  }
+
+  #[tokio::test]
+  async fn test_release_without_sinks_should_keep_receipt() {
+    let state = crate::test_state(ReceiptSinks::default());
+    state.quarantine.hold(&test_payload("rct_1")).unwrap();
+
+    let Err((status, _)) = release(State(state.clone()), Path("rct_1".to_string())).await else {
+      panic!("Releasing without sinks should fail");
+    };
+    assert_eq!(status, http::StatusCode::CONFLICT);
+    assert_eq!(state.quarantine.list().unwrap().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_discarded_receipt_should_not_be_forwarded() {
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+    let state = crate::test_state(ReceiptSinks::default().with_sink(RecordingSink {
+      delivered: delivered.clone(),
+    }));
+    state.quarantine.hold(&test_payload("rct_1")).unwrap();
+
+    let status = discard(State(state.clone()), Path("rct_1".to_string()))
+      .await
+      .unwrap();
+    assert_eq!(status, http::StatusCode::NO_CONTENT);
+    assert!(state.quarantine.list().unwrap().is_empty());
+    assert!(delivered.lock().unwrap().is_empty());
+
+    let Err((status, _)) = discard(State(state), Path("rct_1".to_string())).await else {
+      panic!("A discarded receipt should no longer be quarantined");
+    };
+    assert_eq!(status, http::StatusCode::NOT_FOUND);
+  }
+}
@@ -1,3 +1,127 @@
 pub fn get_webhook_secret() -> String {
   std::env::var("VERSA_WEBHOOK_SECRET").expect("VERSA_WEBHOOK_SECRET must be set")
 }
+
+pub fn get_import_concurrency() -> usize {
+  util::get_env_or("VERSA_IMPORT_CONCURRENCY", 8)
+}
+
+pub fn get_max_import_bytes() -> usize {
+  util::get_env_or("VERSA_MAX_IMPORT_BYTES", 64 * 1024 * 1024)
+}
+
+pub fn get_customer_ledger_path() -> String {
+  std::env::var("VERSA_RECEIVER_CUSTOMERS_PATH").unwrap_or("versa_receiver_customers.db".into())
+}
+
+/// Checks that received receipts' amounts add up, reporting misuse when they do not
+pub fn get_consistency_checks_enabled() -> bool {
+  util::get_env_or("VERSA_RECEIVER_CONSISTENCY_CHECKS", false)
+}
+
+pub fn get_validation_policy() -> crate::quarantine::ValidationPolicy {
+  match std::env::var("VERSA_RECEIVER_VALIDATION_POLICY") {
+    Ok(val) => val
+      .parse()
+      .expect("Invalid VERSA_RECEIVER_VALIDATION_POLICY"),
+    Err(_) => crate::quarantine::ValidationPolicy::Warn,
+  }
+}
+
+pub fn get_quarantine_path() -> String {
+  std::env::var("VERSA_QUARANTINE_PATH").unwrap_or("versa_quarantine.db".into())
+}
+
+pub fn get_local_target_url() -> Option<String> {
+  std::env::var("LOCAL_TARGET_URL").ok()
+}
+
+/// Signs receipts forwarded to `LOCAL_TARGET_URL` when set. Must differ from the webhook
+/// secret, so that local consumers cannot forge webhooks.
+pub fn get_forwarding_secret() -> Option<String> {
+  std::env::var("VERSA_FORWARDING_SECRET").ok()
+}
+
+/// Names of the sinks received receipts are delivered to. Defaults to the http sink when
+/// `LOCAL_TARGET_URL` is set.
+pub fn get_receipt_sinks() -> Vec<String> {
+  match std::env::var("VERSA_RECEIPT_SINKS") {
+    Ok(val) => val
+      .split(',')
+      .map(str::trim)
+      .filter(|name| !name.is_empty())
+      .map(String::from)
+      .collect(),
+    Err(_) => get_local_target_url()
+      .map(|_| vec!["http".to_string()])
+      .unwrap_or_default(),
+  }
+}
+
+pub fn get_sink_jsonl_path() -> String {
+  std::env::var("VERSA_SINK_JSONL_PATH").unwrap_or("versa_receipts.jsonl".into())
+}
+
+pub fn get_sink_directory() -> String {
+  std::env::var("VERSA_SINK_DIRECTORY").unwrap_or("versa_receipts".into())
+}
+
+pub fn get_sink_command() -> Option<String> {
+  std::env::var("VERSA_SINK_COMMAND").ok()
+}
+
+/// Time allowed for the http sink's local target to respond, and for the command sink's
+/// command to exit, which must be shorter than the forwarding lease
+pub fn get_sink_timeout_secs() -> u64 {
+  util::get_env_or("VERSA_SINK_TIMEOUT_SECS", 30)
+}
+
+pub fn get_forwarding_path() -> String {
+  std::env::var("VERSA_FORWARDING_PATH").unwrap_or("versa_forwarding.db".into())
+}
+
+pub fn get_forwarding_max_attempts() -> u32 {
+  util::get_env_or("VERSA_FORWARDING_MAX_ATTEMPTS", 8)
+}
+
+pub fn get_forwarding_retry_base_secs() -> i64 {
+  util::get_env_or("VERSA_FORWARDING_RETRY_BASE_SECS", 30)
+}
+
+/// How long an attempt holds its forward before the worker may retry it
+pub fn get_forwarding_lease_secs() -> i64 {
+  util::get_env_or("VERSA_FORWARDING_LEASE_SECS", 60)
+}
+
+pub fn get_forwarding_poll_secs() -> u64 {
+  util::get_env_or("VERSA_FORWARDING_POLL_SECS", 15)
+}
+
+pub fn get_inbox_path() -> String {
+  std::env::var("VERSA_INBOX_PATH").unwrap_or("versa_inbox.db".into())
+}
+
+/// How far a signed `X-Request-Timestamp`, or the `delivery_at` of a signed body, may be
+/// from now before the webhook is rejected
+pub fn get_webhook_tolerance_secs() -> i64 {
+  util::get_env_or("VERSA_WEBHOOK_TOLERANCE_SECS", 300)
+}
+
+/// Rejects webhooks without an `X-Request-Timestamp`, whose signature covers the body only
+/// and so can be replayed once the receipt is no longer remembered
+pub fn get_webhook_timestamp_required() -> bool {
+  util::get_env_or("VERSA_WEBHOOK_REQUIRE_TIMESTAMP", false)
+}
+
+pub fn get_processed_receipts_path() -> String {
+  std::env::var("VERSA_PROCESSED_RECEIPTS_PATH").unwrap_or("versa_processed_receipts.db".into())
+}
+
+pub fn get_processed_receipt_retention_secs() -> i64 {
+  util::get_env_or("VERSA_PROCESSED_RECEIPT_RETENTION_SECS", 30 * 86_400)
+}
+
+/// How long a receipt stays reserved by a webhook that has not finished processing it
+pub fn get_processed_receipt_lease_secs() -> i64 {
+  util::get_env_or("VERSA_PROCESSED_RECEIPT_LEASE_SECS", 120)
+}
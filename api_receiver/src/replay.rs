@@ -0,0 +1,132 @@
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+/// Remembers the receipt_ids already processed, so that a replayed or retried webhook
+/// neither checks out the receipt's key nor forwards the receipt again. A receipt being
+/// processed is reserved for `lease_secs`, after which a request that never finished is
+/// considered abandoned and the receipt can be processed again.
+pub struct ProcessedReceipts {
+  conn: Mutex<Connection>,
+  retention_secs: i64,
+  lease_secs: i64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ReceiptState {
+  /// The receipt was not processed before and is now reserved for this request
+  Started,
+  /// Another request for the same receipt has not finished yet
+  InProgress,
+  /// The receipt was already processed
+  Processed,
+}
+
+impl ProcessedReceipts {
+  pub fn open(path: &str, retention_secs: i64, lease_secs: i64) -> rusqlite::Result<Self> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(
+      "CREATE TABLE IF NOT EXISTS processed_receipts (
+        receipt_id TEXT PRIMARY KEY,
+        processed_at INTEGER,
+        created_at INTEGER NOT NULL
+      );",
+    )?;
+    Ok(Self {
+      conn: Mutex::new(conn),
+      retention_secs,
+      lease_secs,
+    })
+  }
+
+  pub fn begin(&self, receipt_id: &str) -> rusqlite::Result<ReceiptState> {
+    let now = util::unix_timestamp();
+    let mut conn = self.conn.lock().unwrap();
+    let tx = conn.transaction()?;
+
+    tx.execute(
+      "DELETE FROM processed_receipts WHERE created_at <= ?1",
+      params![now - self.retention_secs],
+    )?;
+
+    let existing = tx
+      .query_row(
+        "SELECT processed_at, created_at FROM processed_receipts WHERE receipt_id = ?1",
+        params![receipt_id],
+        |row| Ok((row.get::<_, Option<i64>>(0)?, row.get::<_, i64>(1)?)),
+      )
+      .optional()?;
+
+    let state = match existing {
+      Some((Some(_), _)) => ReceiptState::Processed,
+      Some((None, created_at)) if created_at > now - self.lease_secs => ReceiptState::InProgress,
+      Some((None, _)) => {
+        tx.execute(
+          "UPDATE processed_receipts SET created_at = ?2 WHERE receipt_id = ?1",
+          params![receipt_id, now],
+        )?;
+        ReceiptState::Started
+      }
+      None => {
+        tx.execute(
+          "INSERT INTO processed_receipts (receipt_id, created_at) VALUES (?1, ?2)",
+          params![receipt_id, now],
+        )?;
+        ReceiptState::Started
+      }
+    };
+
+    tx.commit()?;
+    Ok(state)
+  }
+
+  pub fn complete(&self, receipt_id: &str) -> rusqlite::Result<()> {
+    let conn = self.conn.lock().unwrap();
+    conn.execute(
+      "UPDATE processed_receipts SET processed_at = ?2 WHERE receipt_id = ?1",
+      params![receipt_id, util::unix_timestamp()],
+    )?;
+    Ok(())
+  }
+
+  /// Forgets a reserved receipt after processing failed, so it can be sent again
+  pub fn release(&self, receipt_id: &str) -> rusqlite::Result<()> {
+    let conn = self.conn.lock().unwrap();
+    conn.execute(
+      "DELETE FROM processed_receipts WHERE receipt_id = ?1 AND processed_at IS NULL",
+      params![receipt_id],
+    )?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  #[test]
+  fn test_processed_receipt_should_be_reported_as_duplicate() {
+    let store = ProcessedReceipts::open(":memory:", 3600, 120).unwrap();
+
+    assert_eq!(store.begin("rct_1").unwrap(), ReceiptState::Started);
+    assert_eq!(store.begin("rct_1").unwrap(), ReceiptState::InProgress);
+    store.release("rct_1").unwrap();
+
+    assert_eq!(store.begin("rct_1").unwrap(), ReceiptState::Started);
+    store.complete("rct_1").unwrap();
+    assert_eq!(store.begin("rct_1").unwrap(), ReceiptState::Processed);
+  }
+
+  #[test]
+  fn test_abandoned_receipt_should_start_again_once_its_lease_expires() {
+    // With no lease, a receipt that is not complete is abandoned right away
+    let store = ProcessedReceipts::open(":memory:", 3600, 0).unwrap();
+
+    assert_eq!(store.begin("rct_1").unwrap(), ReceiptState::Started);
+    assert_eq!(store.begin("rct_1").unwrap(), ReceiptState::Started);
+    store.complete("rct_1").unwrap();
+    assert_eq!(store.begin("rct_1").unwrap(), ReceiptState::Processed);
+  }
+}
@@ -1,3 +1,4 @@
+use protocol::consistency::{Finding, Severity};
 use tracing::info;
 use versa::protocol::misuse::{Misuse, MisuseCode, ReportMisuseRequest};
 
@@ -6,18 +7,46 @@ pub async fn send(
   client_secret: &str,
   receipt_id: String,
   misuse_code: MisuseCode,
+) -> Result<(), ()> {
+  let misuse = vec![Misuse {
+    code: misuse_code,
+    rule: None,
+    description: None,
+  }];
+  send_all(client_id, client_secret, receipt_id, misuse).await
+}
+
+/// Reports each consistency finding of severity error as a misuse, with the finding's
+/// rule and message
+pub async fn send_findings(
+  client_id: &str,
+  client_secret: &str,
+  receipt_id: String,
+  misuse_code: MisuseCode,
+  findings: &[Finding],
+) -> Result<(), ()> {
+  let misuse = findings
+    .iter()
+    .filter(|finding| finding.severity == Severity::Error)
+    .map(|finding| Misuse {
+      code: misuse_code.clone(),
+      rule: Some(finding.rule.to_string()),
+      description: Some(finding.to_string()),
+    })
+    .collect();
+  send_all(client_id, client_secret, receipt_id, misuse).await
+}
+
+async fn send_all(
+  client_id: &str,
+  client_secret: &str,
+  receipt_id: String,
+  misuse: Vec<Misuse>,
 ) -> Result<(), ()> {
   let registry_url = std::env::var("REGISTRY_URL").unwrap_or_default();
   let credential = format!("Basic {}:{}", client_id, client_secret);
 
-  let payload = ReportMisuseRequest {
-    receipt_id,
-    misuse: vec![Misuse {
-      code: misuse_code,
-      rule: None,
-      description: None,
-    }],
-  };
+  let payload = ReportMisuseRequest { receipt_id, misuse };
 
   let payload_json = serde_json::to_string(&payload).unwrap();
 
@@ -1,4 +1,6 @@
+use axum::extract::State;
 use http::HeaderMap;
+use protocol::consistency::Severity;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tracing::info;
@@ -6,11 +8,16 @@ use versa::{
   client_receiver::VersaReceiver,
   protocol::{
     customer_registration::HandleType,
+    misuse::MisuseCode,
     webhook::{TransactionEvent, WebhookEvent, WebhookEventType},
     ReceiverPayload, Sender, TransactionHandles,
   },
 };
 
+use crate::quarantine::ValidationPolicy;
+use crate::replay::ReceiptState;
+use crate::ReceiverState;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DecryptedPayload {
   pub handles: TransactionHandles,
@@ -21,13 +28,57 @@ pub struct DecryptedPayload {
   pub sender_client_id: String,
   pub sender: Option<Sender>,
   pub transaction_id: String,
+  /// Present when the receipt failed validation and was forwarded anyway
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub validation_failure: Option<ValidationFailureAnnotation>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ValidationFailureAnnotation {
+  pub misuse_code: MisuseCode,
+  pub errors: Vec<String>,
+}
+
+/// A decrypted receipt with placeholder details, for tests of the receipt stores and sinks
+#[cfg(test)]
+pub(crate) fn test_payload(receipt_id: &str) -> DecryptedPayload {
+  DecryptedPayload {
+    handles: TransactionHandles::new().with_customer_email("jane@example.com".into()),
+    receipt_id: receipt_id.into(),
+    receipt: serde_json::json!({ "schema_version": "2.0.0" }),
+    receiver_client_id: "versa_cid_receiver".into(),
+    schema_version: "2.0.0".into(),
+    sender_client_id: "versa_cid_sender".into(),
+    sender: None,
+    transaction_id: "txn_123".into(),
+    validation_failure: None,
+  }
+}
+
+/// Without an `X-Request-Timestamp`, the signature only covers the body, so its
+/// `delivery_at`, or else its `event_at`, must be within the tolerance instead
+fn check_signed_timestamp<T>(
+  body: &WebhookEvent<T>,
+  tolerance_secs: i64,
+  now: i64,
+) -> Result<(), String> {
+  let Some(signed_at) = body.delivery_at.or(body.event_at) else {
+    return Ok(());
+  };
+  if (now - signed_at).abs() > tolerance_secs {
+    return Err(format!(
+      "Webhook delivery_at {} is outside the tolerance of {}s",
+      signed_at, tolerance_secs
+    ));
+  }
+  Ok(())
 }
 
 pub async fn target(
+  State(state): State<ReceiverState>,
   headers: HeaderMap,
   raw_body: axum::body::Body,
 ) -> Result<axum::http::StatusCode, (axum::http::StatusCode, String)> {
-  let (receiver_client_id, receiver_client_secret) = util::get_client_id_and_client_secret();
   let receiver_secret = crate::r_config::get_webhook_secret();
 
   let Some(request_signature) = headers.get("X-Request-Signature") else {
@@ -42,14 +93,58 @@ pub async fn target(
       "Malformed X-Request-Signature header".to_string(),
     ));
   };
-  let (verified, body_bytes) =
-    crate::hmac_verify::verify_with_secret(raw_body, receiver_secret.clone(), request_token).await;
-  if !verified {
-    return Err((
-      http::StatusCode::UNAUTHORIZED,
-      "Failed to verify request signature".to_string(),
-    ));
-  }
+  let request_timestamp = match headers.get("X-Request-Timestamp") {
+    Some(val) => Some(val.to_str().map_err(|_| {
+      (
+        http::StatusCode::BAD_REQUEST,
+        "Malformed X-Request-Timestamp header".to_string(),
+      )
+    })?),
+    None if crate::r_config::get_webhook_timestamp_required() => {
+      return Err((
+        http::StatusCode::BAD_REQUEST,
+        "Missing X-Request-Timestamp header".to_string(),
+      ));
+    }
+    None => None,
+  };
+  let body_bytes = match request_timestamp {
+    // A timestamped signature covers `{timestamp}.{body}`, and stale timestamps are replays
+    Some(timestamp) => {
+      let body_bytes = axum::body::to_bytes(raw_body, 512_000_000)
+        .await
+        .map_err(|e| {
+          (
+            http::StatusCode::BAD_REQUEST,
+            format!("Failed to read body: {}", e),
+          )
+        })?;
+      protocol::hmac_util::verify_timestamped_token(
+        &body_bytes,
+        timestamp,
+        request_token,
+        &receiver_secret,
+        crate::r_config::get_webhook_tolerance_secs(),
+        util::unix_timestamp(),
+      )
+      .map_err(|e| {
+        info!("WARN: Rejected webhook: {}", e);
+        (http::StatusCode::UNAUTHORIZED, e)
+      })?;
+      body_bytes
+    }
+    None => {
+      let (verified, body_bytes) =
+        crate::hmac_verify::verify_with_secret(raw_body, receiver_secret, request_token).await;
+      if !verified {
+        return Err((
+          http::StatusCode::UNAUTHORIZED,
+          "Failed to verify request signature".to_string(),
+        ));
+      }
+      body_bytes
+    }
+  };
   info!("Successfully verified hmac request signature");
   let body: WebhookEvent<ReceiverPayload> = match serde_json::from_slice(&body_bytes) {
     Ok(val) => val,
@@ -61,6 +156,18 @@ pub async fn target(
     }
   };
 
+  if request_timestamp.is_none() {
+    check_signed_timestamp(
+      &body,
+      crate::r_config::get_webhook_tolerance_secs(),
+      util::unix_timestamp(),
+    )
+    .map_err(|e| {
+      info!("WARN: Rejected webhook: {}", e);
+      (http::StatusCode::UNAUTHORIZED, e)
+    })?;
+  }
+
   let event = body.event;
   let transaction_event: TransactionEvent = match event {
     WebhookEventType::Receipt => TransactionEvent::Receipt,
@@ -73,13 +180,62 @@ pub async fn target(
     }
   };
 
+  let receipt_id = body.data.receipt_id.clone();
+  match state.processed.begin(&receipt_id) {
+    Ok(ReceiptState::Started) => {}
+    Ok(ReceiptState::Processed) => {
+      info!(
+        "Receipt {} was already processed, ignoring duplicate",
+        receipt_id
+      );
+      return Ok(http::StatusCode::OK);
+    }
+    Ok(ReceiptState::InProgress) => {
+      return Err((
+        http::StatusCode::CONFLICT,
+        format!("Receipt {} is already being processed", receipt_id),
+      ));
+    }
+    Err(e) => {
+      info!("Failed to read processed receipts: {:?}", e);
+      return Err((
+        http::StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to read processed receipts".to_string(),
+      ));
+    }
+  }
+
+  // Forget the receipt when processing fails, so that the sender's retry is processed
+  let result = receive(&state, transaction_event, body.data).await;
+  let recorded = match result {
+    Ok(_) => state.processed.complete(&receipt_id),
+    Err(_) => state.processed.release(&receipt_id),
+  };
+  if let Err(e) = recorded {
+    info!("Failed to record processed receipt: {:?}", e);
+  }
+  result
+}
+
+async fn receive(
+  state: &ReceiverState,
+  transaction_event: TransactionEvent,
+  payload: ReceiverPayload,
+) -> Result<axum::http::StatusCode, (axum::http::StatusCode, String)> {
+  let (receiver_client_id, receiver_client_secret) = util::get_client_id_and_client_secret();
+  let receiver_secret = crate::r_config::get_webhook_secret();
+
   let ReceiverPayload {
     sender_client_id,
     receipt_id,
     envelope,
-  } = body.data;
+  } = payload;
 
   info!("Received envelope from sender={}", sender_client_id);
+
+  // Decided before the key is checked out, so that an invalid policy fails the webhook
+  // without consuming the key
+  let policy = crate::r_config::get_validation_policy();
   info!("Checking out key for receipt_id={}", receipt_id);
 
   let versa_client =
@@ -97,14 +253,20 @@ pub async fn target(
   let data = match versa_client.decrypt_envelope::<Value>(envelope, checkout.key) {
     Ok(val) => val,
     Err(misuse_code) => {
-      crate::report_misuse::send(
+      if crate::report_misuse::send(
         &receiver_client_id,
         &receiver_client_secret,
-        checkout.receipt_id,
+        checkout.receipt_id.clone(),
         misuse_code.clone(),
       )
       .await
-      .expect("Reporting misuse failed");
+      .is_err()
+      {
+        info!(
+          "WARN: Failed to report misuse for receipt {}",
+          checkout.receipt_id
+        );
+      }
       return Err((
         http::StatusCode::BAD_REQUEST,
         format!("Failed to decrypt envelope: {:?}", misuse_code),
@@ -118,22 +280,82 @@ pub async fn target(
     serde_json::to_string(&data).unwrap()
   );
 
-  match crate::schema::validate(&transaction_event, &data).await {
+  let mut validation_failure = None;
+  match crate::schema::validate(&state.validators, &transaction_event, &data).await {
     Ok(val) => val,
     Err((misuse_code, msg)) => {
       info!("WARN: Schema validation failed: {}", msg);
-      crate::report_misuse::send(
+      if crate::report_misuse::send(
         &receiver_client_id,
         &receiver_client_secret,
         checkout.receipt_id.clone(),
         misuse_code.clone(),
       )
       .await
-      .expect("Reporting misuse failed");
+      .is_err()
+      {
+        info!(
+          "WARN: Failed to report misuse for receipt {}",
+          checkout.receipt_id
+        );
+      }
       info!("WARN: Failed to validate receipt data: {:?}", misuse_code);
+      validation_failure = Some(ValidationFailureAnnotation {
+        misuse_code,
+        errors: vec![msg],
+      });
     }
   };
 
+  if matches!(transaction_event, TransactionEvent::Receipt)
+    && crate::r_config::get_consistency_checks_enabled()
+  {
+    let findings = protocol::consistency::check_receipt(&data);
+    for finding in &findings {
+      info!("WARN: Receipt consistency finding: {}", finding);
+    }
+    if let Some(misuse_code) = protocol::consistency::misuse_code(&findings) {
+      if crate::report_misuse::send_findings(
+        &receiver_client_id,
+        &receiver_client_secret,
+        checkout.receipt_id.clone(),
+        misuse_code.clone(),
+        &findings,
+      )
+      .await
+      .is_err()
+      {
+        info!(
+          "WARN: Failed to report misuse for receipt {}",
+          checkout.receipt_id
+        );
+      }
+      let errors = findings
+        .iter()
+        .filter(|finding| finding.severity == Severity::Error)
+        .map(ToString::to_string);
+      match &mut validation_failure {
+        Some(annotation) => annotation.errors.extend(errors),
+        None => {
+          validation_failure = Some(ValidationFailureAnnotation {
+            misuse_code,
+            errors: errors.collect(),
+          })
+        }
+      }
+    }
+  }
+
+  if let (Some(annotation), ValidationPolicy::Reject) = (&validation_failure, policy) {
+    return Err((
+      http::StatusCode::UNPROCESSABLE_ENTITY,
+      format!(
+        "Receipt failed validation: {}",
+        annotation.errors.join("; ")
+      ),
+    ));
+  }
+
   let payload = DecryptedPayload {
     handles: checkout.handles,
     receipt_id: checkout.receipt_id,
@@ -143,6 +365,7 @@ pub async fn target(
     sender_client_id,
     sender: checkout.sender,
     transaction_id: checkout.transaction_id,
+    validation_failure,
   };
 
   info!(
@@ -150,28 +373,42 @@ pub async fn target(
     payload
   );
 
-  match std::env::var("LOCAL_TARGET_URL") {
-    Ok(url) => {
-      // send to url
-      let client = reqwest::Client::new();
-      let res = client.post(url).json(&payload).send().await.map_err(|e| {
-        (
-          http::StatusCode::INTERNAL_SERVER_ERROR,
-          format!("Failed to send data to local target: {:?}", e),
-        )
-      })?;
+  let event_name = protocol::schema::event_name(&transaction_event);
+  if let Err(e) = state.inbox.record(&event_name, &payload) {
+    info!("Failed to record receipt in inbox: {:?}", e);
+  }
 
-      if res.status().is_success() {
-        info!("Successfully sent data to local target");
-      } else {
-        info!("Failed to send data to local target: {:?}", res);
-      }
-    }
-    Err(_) => {
-      info!("WARN: LOCAL_TARGET_URL not set, data not sent to local endpoint");
-    }
+  if payload.validation_failure.is_some() && policy == ValidationPolicy::Quarantine {
+    state.quarantine.hold(&payload).map_err(|e| {
+      info!("Failed to quarantine receipt: {:?}", e);
+      (
+        http::StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to quarantine receipt".to_string(),
+      )
+    })?;
+    info!(
+      "WARN: Receipt {} failed validation and was quarantined",
+      payload.receipt_id
+    );
+    return Ok(http::StatusCode::ACCEPTED);
   }
 
+  if state.sinks.is_empty() {
+    info!("WARN: No receipt sinks configured, data not delivered");
+    return Ok(http::StatusCode::OK);
+  }
+  // Failed deliveries are retried from the forwarding queue, so the webhook succeeds
+  // once the decrypted payload is persisted
+  crate::forwarding::forward(&state.forwarding, &state.sinks, &payload)
+    .await
+    .map_err(|e| {
+      info!("Failed to persist receipt for forwarding: {:?}", e);
+      (
+        http::StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to persist receipt for forwarding".to_string(),
+      )
+    })?;
+
   Ok(http::StatusCode::OK)
 }
 
@@ -182,6 +419,7 @@ pub struct ReceiverCustomerReference {
 }
 
 pub async fn register_customer(
+  axum::extract::State(state): axum::extract::State<crate::ReceiverState>,
   axum::extract::Json(payload): axum::extract::Json<ReceiverCustomerReference>,
 ) -> http::StatusCode {
   let (client_id, client_secret) = util::get_client_id_and_client_secret();
@@ -196,15 +434,30 @@ pub async fn register_customer(
     .with_client_string(&util::get_client_string())
     .receiving_client(receiver_secret);
 
-  match protocol::customer_registration::register_customer(versa_client, handle, handle_type, None)
-    .await
+  let handle_type_name = protocol::customer_registration::handle_type_name(&handle_type);
+  match protocol::customer_registration::register_customer(
+    versa_client,
+    handle.clone(),
+    handle_type,
+    None,
+  )
+  .await
   {
-    Ok(_) => http::StatusCode::OK,
+    Ok(_) => {
+      if let Err(e) = state
+        .customers
+        .record_registered(&handle, &handle_type_name, None)
+      {
+        info!("Failed to record customer in ledger: {:?}", e);
+      }
+      http::StatusCode::OK
+    }
     Err(_) => http::StatusCode::SERVICE_UNAVAILABLE,
   }
 }
 
 pub async fn deregister_customer(
+  axum::extract::State(state): axum::extract::State<crate::ReceiverState>,
   axum::extract::Json(payload): axum::extract::Json<ReceiverCustomerReference>,
 ) -> http::StatusCode {
   let (client_id, client_secret) = util::get_client_id_and_client_secret();
@@ -219,15 +472,71 @@ pub async fn deregister_customer(
     .with_client_string(&util::get_client_string())
     .receiving_client(receiver_secret);
 
+  let handle_type_name = protocol::customer_registration::handle_type_name(&handle_type);
   match protocol::customer_registration::deregister_customer(
     versa_client,
-    handle,
+    handle.clone(),
     handle_type,
     None,
   )
   .await
   {
-    Ok(_) => http::StatusCode::OK,
+    Ok(_) => {
+      if let Err(e) = state
+        .customers
+        .record_deregistered(&handle, &handle_type_name, None)
+      {
+        info!("Failed to record customer in ledger: {:?}", e);
+      }
+      http::StatusCode::OK
+    }
     Err(_) => http::StatusCode::SERVICE_UNAVAILABLE,
   }
 }
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  async fn signed_request(event: &str, delivery_at: i64) -> (HeaderMap, axum::body::Body) {
+    let body = serde_json::to_vec(&serde_json::json!({
+      "event": event,
+      "event_id": "evt_123",
+      "event_at": delivery_at,
+      "delivery_id": null,
+      "delivery_at": delivery_at,
+      "data": {
+        "sender_client_id": "versa_cid_sender",
+        "receipt_id": "rct_123",
+        "envelope": { "encrypted": "", "nonce": "" }
+      }
+    }))
+    .unwrap();
+    let token =
+      protocol::hmac_util::generate_token(bytes::Bytes::from(body.clone()), "whsec_test".into())
+        .await;
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Request-Signature", token.parse().unwrap());
+    (headers, axum::body::Body::from(body))
+  }
+
+  #[tokio::test]
+  async fn test_body_signed_webhook_should_be_rejected_when_delivery_at_is_stale() {
+    std::env::set_var("VERSA_WEBHOOK_SECRET", "whsec_test");
+    let state = crate::test_state(crate::sinks::ReceiptSinks::default());
+
+    let (headers, body) = signed_request("receipt", util::unix_timestamp() - 3600).await;
+    let (status, message) = target(State(state.clone()), headers, body)
+      .await
+      .unwrap_err();
+    assert_eq!(status, http::StatusCode::UNAUTHORIZED);
+    assert!(message.contains("outside the tolerance"));
+
+    // A fresh delivery gets past the check, to be turned away for its event type
+    let (headers, body) = signed_request("receipt.decrypted", util::unix_timestamp()).await;
+    let (status, _) = target(State(state), headers, body).await.unwrap_err();
+    assert_eq!(status, http::StatusCode::BAD_REQUEST);
+  }
+}
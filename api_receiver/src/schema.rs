@@ -1,9 +1,39 @@
+use axum::extract::State;
+use protocol::schema::{ValidationFailure, ValidationMetrics, ValidatorCache};
 use serde_json::Value;
+use tracing::info;
 use versa::protocol::{misuse::MisuseCode, webhook::TransactionEvent};
 
-pub async fn validate(event: &TransactionEvent, data: &Value) -> Result<(), (MisuseCode, String)> {
-  let validator = versa::schema::validator::Validator::new().allow_remote_lookup(true);
-  validator.validate(event, data).await
+/// Validates received data against the bundled schema for its `schema_version`, reporting
+/// the first violation like `versa`'s validator. Receipts at versions that are not bundled
+/// are let through, unless remote lookup is enabled and their schema can be fetched.
+pub async fn validate(
+  validators: &ValidatorCache,
+  event: &TransactionEvent,
+  data: &Value,
+) -> Result<(), (MisuseCode, String)> {
+  let Some(schema_version) = data.get("schema_version").and_then(Value::as_str) else {
+    return Err((
+      MisuseCode::SchemaVersionInvalid,
+      "Missing schema_version".to_string(),
+    ));
+  };
+
+  match validators.validate(event, schema_version, data).await {
+    Ok(()) => Ok(()),
+    Err(ValidationFailure::Invalid { code, errors }) => {
+      Err((code, errors.into_iter().next().unwrap_or_default()))
+    }
+    Err(ValidationFailure::SchemaUnavailable(e)) => {
+      info!("WARN: Skipping schema validation: {}", e);
+      Ok(())
+    }
+  }
+}
+
+/// Validation counts and timings per event and schema version
+pub async fn metrics(State(state): State<crate::ReceiverState>) -> axum::Json<ValidationMetrics> {
+  axum::Json(state.validators.metrics())
 }
 
 #[cfg(test)]
@@ -151,7 +181,13 @@ mod tests {
       ]
     });
 
-    assert!(validate(&TransactionEvent::Receipt, &data).await.is_ok());
+    assert!(validate(
+      &ValidatorCache::default(),
+      &TransactionEvent::Receipt,
+      &data
+    )
+    .await
+    .is_ok());
   }
 
   #[tokio::test]
@@ -208,13 +244,19 @@ mod tests {
       "payments": []
     });
 
-    assert!(validate(&TransactionEvent::Receipt, &data).await.is_ok());
+    assert!(validate(
+      &ValidatorCache::default(),
+      &TransactionEvent::Receipt,
+      &data
+    )
+    .await
+    .is_ok());
   }
 
   #[tokio::test]
   async fn test_validation_of_incomplete_receipt_should_fail() {
     let data = serde_json::json!({
-      "schema_version": "1.10.0",
+      "schema_version": "2.0.0",
       "header": {
         "invoice_number": "auth_1MzFN1K8F4fqH0lBmFq8CjbU",
         "currency": "usd",
@@ -236,7 +278,13 @@ mod tests {
       },
     });
 
-    let Err((code, msg)) = validate(&TransactionEvent::Receipt, &data).await else {
+    let Err((code, msg)) = validate(
+      &ValidatorCache::default(),
+      &TransactionEvent::Receipt,
+      &data,
+    )
+    .await
+    else {
       panic!("This test validation case should fail");
     };
     assert_eq!(code, MisuseCode::SchemaValidationFailed);
@@ -268,7 +316,13 @@ mod tests {
       },
     });
 
-    let Err((code, msg)) = validate(&TransactionEvent::Receipt, &data).await else {
+    let Err((code, msg)) = validate(
+      &ValidatorCache::default(),
+      &TransactionEvent::Receipt,
+      &data,
+    )
+    .await
+    else {
       panic!("This test validation case should fail");
     };
     assert_eq!(code, MisuseCode::SchemaVersionInvalid);
@@ -296,7 +350,13 @@ mod tests {
       },
     });
 
-    let Err((code, msg)) = validate(&TransactionEvent::Itinerary, &data).await else {
+    let Err((code, msg)) = validate(
+      &ValidatorCache::default(),
+      &TransactionEvent::Itinerary,
+      &data,
+    )
+    .await
+    else {
       panic!("This test validation case should fail");
     };
     assert_eq!(code, MisuseCode::SchemaValidationFailed);
@@ -0,0 +1,456 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::AsyncWriteExt;
+
+use crate::routes::DecryptedPayload;
+
+/// A destination for receipts once they are received, decrypted and validated
+#[async_trait]
+pub trait ReceiptSink: Send + Sync {
+  /// Name of the sink, as used in `VERSA_RECEIPT_SINKS`
+  fn name(&self) -> &str;
+
+  /// Delivers the payload, returning an error if the sink did not accept it
+  async fn deliver(&self, payload: &DecryptedPayload) -> Result<(), String>;
+}
+
+/// POSTs the payload as JSON to a local web service. With a secret, the body is signed
+/// like a timestamped webhook, in the `X-Request-Timestamp` and `X-Request-Signature`
+/// headers, which `protocol::hmac_util::verify_timestamped_token` verifies.
+pub struct HttpSink {
+  pub url: String,
+  pub secret: Option<String>,
+  /// Time allowed for the local target to respond
+  pub timeout: Duration,
+}
+
+#[async_trait]
+impl ReceiptSink for HttpSink {
+  fn name(&self) -> &str {
+    "http"
+  }
+
+  async fn deliver(&self, payload: &DecryptedPayload) -> Result<(), String> {
+    let body = serde_json::to_vec(payload).map_err(|e| e.to_string())?;
+    let client = reqwest::Client::builder()
+      .timeout(self.timeout)
+      .build()
+      .map_err(|e| format!("Failed to build client for local target: {:?}", e))?;
+    let mut req = client
+      .post(&self.url)
+      .header("Content-Type", "application/json");
+    if let Some(secret) = &self.secret {
+      let timestamp = util::unix_timestamp();
+      req = req
+        .header("X-Request-Timestamp", timestamp.to_string())
+        .header(
+          "X-Request-Signature",
+          protocol::hmac_util::generate_timestamped_token(timestamp, &body, secret),
+        );
+    }
+
+    let res = req
+      .body(body)
+      .send()
+      .await
+      .map_err(|e| format!("Failed to send data to local target: {:?}", e))?;
+
+    if !res.status().is_success() {
+      return Err(format!("Local target responded with {}", res.status()));
+    }
+    Ok(())
+  }
+}
+
+/// Appends the payload as one line of JSON to a file
+pub struct JsonlFileSink {
+  pub path: PathBuf,
+  /// Serializes appends so that concurrent receipts do not interleave
+  lock: tokio::sync::Mutex<()>,
+}
+
+impl JsonlFileSink {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self {
+      path: path.into(),
+      lock: tokio::sync::Mutex::new(()),
+    }
+  }
+}
+
+#[async_trait]
+impl ReceiptSink for JsonlFileSink {
+  fn name(&self) -> &str {
+    "jsonl"
+  }
+
+  async fn deliver(&self, payload: &DecryptedPayload) -> Result<(), String> {
+    let mut line = serde_json::to_vec(payload).map_err(|e| e.to_string())?;
+    line.push(b'\n');
+
+    let _guard = self.lock.lock().await;
+    let mut file = tokio::fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&self.path)
+      .await
+      .map_err(|e| format!("Failed to open {}: {}", self.path.display(), e))?;
+    file
+      .write_all(&line)
+      .await
+      .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+    // Writes complete in the background until flushed
+    file
+      .flush()
+      .await
+      .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))
+  }
+}
+
+/// Writes each payload to its own `{receipt_id}.json` file in a directory
+pub struct DirectorySink {
+  pub dir: PathBuf,
+}
+
+/// The receipt id with every character that is not alphanumeric, `-` or `_` replaced,
+/// so that a receipt cannot be written outside the sink's directory
+fn file_stem(receipt_id: &str) -> String {
+  receipt_id
+    .chars()
+    .map(|c| {
+      if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+        c
+      } else {
+        '_'
+      }
+    })
+    .collect()
+}
+
+#[async_trait]
+impl ReceiptSink for DirectorySink {
+  fn name(&self) -> &str {
+    "directory"
+  }
+
+  async fn deliver(&self, payload: &DecryptedPayload) -> Result<(), String> {
+    let body = serde_json::to_vec_pretty(payload).map_err(|e| e.to_string())?;
+    tokio::fs::create_dir_all(&self.dir)
+      .await
+      .map_err(|e| format!("Failed to create {}: {}", self.dir.display(), e))?;
+
+    // Write to a temporary file first so readers never see a partial receipt
+    let stem = file_stem(&payload.receipt_id);
+    let path = self.dir.join(format!("{}.json", stem));
+    let tmp = self.dir.join(format!(".{}.json.tmp", stem));
+    tokio::fs::write(&tmp, body)
+      .await
+      .map_err(|e| format!("Failed to write {}: {}", tmp.display(), e))?;
+    tokio::fs::rename(&tmp, &path)
+      .await
+      .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+  }
+}
+
+/// Prints the payload as one line of JSON to standard output
+pub struct StdoutSink;
+
+#[async_trait]
+impl ReceiptSink for StdoutSink {
+  fn name(&self) -> &str {
+    "stdout"
+  }
+
+  async fn deliver(&self, payload: &DecryptedPayload) -> Result<(), String> {
+    let mut line = serde_json::to_vec(payload).map_err(|e| e.to_string())?;
+    line.push(b'\n');
+    let mut stdout = tokio::io::stdout();
+    stdout.write_all(&line).await.map_err(|e| e.to_string())?;
+    stdout.flush().await.map_err(|e| e.to_string())
+  }
+}
+
+/// Runs a shell command for each payload, writing the payload's JSON to its standard
+/// input. The receipt id is available to the command as `VERSA_RECEIPT_ID`, and the
+/// receipt is accepted when the command exits successfully. A command that is still
+/// running after the timeout is killed.
+pub struct CommandSink {
+  pub command: String,
+  pub timeout: Duration,
+}
+
+#[async_trait]
+impl ReceiptSink for CommandSink {
+  fn name(&self) -> &str {
+    "command"
+  }
+
+  async fn deliver(&self, payload: &DecryptedPayload) -> Result<(), String> {
+    let body = serde_json::to_vec(payload).map_err(|e| e.to_string())?;
+    let mut child = tokio::process::Command::new("sh")
+      .arg("-c")
+      .arg(&self.command)
+      .env("VERSA_RECEIPT_ID", &payload.receipt_id)
+      .stdin(Stdio::piped())
+      .kill_on_drop(true)
+      .spawn()
+      .map_err(|e| format!("Failed to run {}: {}", self.command, e))?;
+
+    let run = async {
+      if let Some(mut stdin) = child.stdin.take() {
+        stdin
+          .write_all(&body)
+          .await
+          .map_err(|e| format!("Failed to write to {}: {}", self.command, e))?;
+      }
+      child.wait().await.map_err(|e| e.to_string())
+    };
+    let status = match tokio::time::timeout(self.timeout, run).await {
+      Ok(status) => status?,
+      Err(_) => {
+        let _ = child.kill().await;
+        return Err(format!(
+          "{} timed out after {}s",
+          self.command,
+          self.timeout.as_secs_f64()
+        ));
+      }
+    };
+    if !status.success() {
+      return Err(format!("{} exited with {}", self.command, status));
+    }
+    Ok(())
+  }
+}
+
+/// The sinks every received receipt is delivered to
+#[derive(Default)]
+pub struct ReceiptSinks {
+  sinks: Vec<Arc<dyn ReceiptSink>>,
+}
+
+impl ReceiptSinks {
+  pub fn with_sink(mut self, sink: impl ReceiptSink + 'static) -> Self {
+    self.sinks.push(Arc::new(sink));
+    self
+  }
+
+  /// Builds the sinks named in `VERSA_RECEIPT_SINKS`, each configured by its own
+  /// environment variables. Panics on unknown sinks or missing configuration.
+  pub fn from_config() -> Self {
+    crate::r_config::get_receipt_sinks()
+      .iter()
+      .fold(Self::default(), |sinks, name| match name.as_str() {
+        "http" => sinks.with_sink(HttpSink {
+          url: crate::r_config::get_local_target_url()
+            .expect("LOCAL_TARGET_URL must be set for the http sink"),
+          secret: crate::r_config::get_forwarding_secret(),
+          timeout: Duration::from_secs(crate::r_config::get_sink_timeout_secs()),
+        }),
+        "jsonl" => sinks.with_sink(JsonlFileSink::new(crate::r_config::get_sink_jsonl_path())),
+        "directory" => sinks.with_sink(DirectorySink {
+          dir: crate::r_config::get_sink_directory().into(),
+        }),
+        "stdout" => sinks.with_sink(StdoutSink),
+        "command" => sinks.with_sink(CommandSink {
+          command: crate::r_config::get_sink_command()
+            .expect("VERSA_SINK_COMMAND must be set for the command sink"),
+          timeout: Duration::from_secs(crate::r_config::get_sink_timeout_secs()),
+        }),
+        other => panic!("Unknown receipt sink: {}", other),
+      })
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.sinks.is_empty()
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Arc<dyn ReceiptSink>> {
+    self.sinks.iter()
+  }
+
+  /// The first sink with the given name
+  pub fn get(&self, name: &str) -> Option<&Arc<dyn ReceiptSink>> {
+    self.sinks.iter().find(|sink| sink.name() == name)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use std::sync::Mutex;
+
+  use axum::body::Bytes;
+  use axum::extract::State;
+  use http::HeaderMap;
+
+  use super::*;
+  use crate::routes::test_payload;
+  use pretty_assertions::assert_eq;
+
+  /// An empty directory under the system temporary directory, unique to the test
+  fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("versa_sinks_{}_{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  type Requests = Arc<Mutex<Vec<(HeaderMap, Bytes)>>>;
+
+  /// Serves a local target that records every request and responds with `status`
+  async fn serve_target(status: http::StatusCode) -> (String, Requests) {
+    let requests = Requests::default();
+    let app = axum::Router::new()
+      .route(
+        "/",
+        axum::routing::post(
+          move |State(requests): State<Requests>, headers: HeaderMap, body: Bytes| async move {
+            requests.lock().unwrap().push((headers, body));
+            status
+          },
+        ),
+      )
+      .with_state(requests.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (url, requests)
+  }
+
+  #[tokio::test]
+  async fn test_http_sink_should_post_signed_payload() {
+    let (url, requests) = serve_target(http::StatusCode::OK).await;
+    let sink = HttpSink {
+      url,
+      secret: Some("forwarding_secret".into()),
+      timeout: Duration::from_secs(10),
+    };
+    sink.deliver(&test_payload("rct_1")).await.unwrap();
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    let (headers, body) = &requests[0];
+    let payload: DecryptedPayload = serde_json::from_slice(body).unwrap();
+    assert_eq!(payload.receipt_id, "rct_1");
+    assert_eq!(
+      protocol::hmac_util::verify_timestamped_token(
+        body,
+        headers["X-Request-Timestamp"].to_str().unwrap(),
+        headers["X-Request-Signature"].to_str().unwrap(),
+        "forwarding_secret",
+        300,
+        util::unix_timestamp(),
+      ),
+      Ok(())
+    );
+  }
+
+  #[tokio::test]
+  async fn test_http_sink_should_fail_on_error_response() {
+    let (url, requests) = serve_target(http::StatusCode::SERVICE_UNAVAILABLE).await;
+    let sink = HttpSink {
+      url,
+      secret: None,
+      timeout: Duration::from_secs(10),
+    };
+    assert!(sink.deliver(&test_payload("rct_1")).await.is_err());
+
+    let requests = requests.lock().unwrap();
+    assert!(!requests[0].0.contains_key("X-Request-Signature"));
+  }
+
+  #[tokio::test]
+  async fn test_jsonl_file_sink_should_append_one_line_per_payload() {
+    let path = test_dir("jsonl").join("receipts.jsonl");
+    let sink = JsonlFileSink::new(&path);
+    sink.deliver(&test_payload("rct_1")).await.unwrap();
+    sink.deliver(&test_payload("rct_2")).await.unwrap();
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let receipt_ids: Vec<String> = contents
+      .lines()
+      .map(|line| {
+        serde_json::from_str::<DecryptedPayload>(line)
+          .unwrap()
+          .receipt_id
+      })
+      .collect();
+    assert_eq!(receipt_ids, vec!["rct_1".to_string(), "rct_2".to_string()]);
+  }
+
+  #[tokio::test]
+  async fn test_directory_sink_should_write_file_per_receipt() {
+    let dir = test_dir("directory");
+    let sink = DirectorySink {
+      dir: dir.join("receipts"),
+    };
+    sink.deliver(&test_payload("rct_1")).await.unwrap();
+    sink.deliver(&test_payload("../rct_2")).await.unwrap();
+
+    let mut files: Vec<String> = std::fs::read_dir(dir.join("receipts"))
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+      .collect();
+    files.sort();
+    assert_eq!(
+      files,
+      vec!["___rct_2.json".to_string(), "rct_1.json".to_string()]
+    );
+    assert!(!dir.join("rct_2.json").exists());
+
+    let written = std::fs::read(dir.join("receipts").join("rct_1.json")).unwrap();
+    let payload: DecryptedPayload = serde_json::from_slice(&written).unwrap();
+    assert_eq!(payload.transaction_id, "txn_123");
+  }
+
+  #[tokio::test]
+  async fn test_stdout_sink_should_deliver() {
+    assert_eq!(StdoutSink.deliver(&test_payload("rct_1")).await, Ok(()));
+  }
+
+  #[tokio::test]
+  async fn test_command_sink_should_pipe_payload_to_command() {
+    let dir = test_dir("command");
+    let sink = CommandSink {
+      command: format!("cat > {}/$VERSA_RECEIPT_ID.json", dir.display()),
+      timeout: Duration::from_secs(10),
+    };
+    sink.deliver(&test_payload("rct_1")).await.unwrap();
+
+    let written = std::fs::read(dir.join("rct_1.json")).unwrap();
+    let payload: DecryptedPayload = serde_json::from_slice(&written).unwrap();
+    assert_eq!(payload.receipt_id, "rct_1");
+
+    let failing = CommandSink {
+      command: "cat > /dev/null; exit 3".into(),
+      timeout: Duration::from_secs(10),
+    };
+    assert!(failing.deliver(&test_payload("rct_1")).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_sinks_should_fail_once_their_timeout_elapses() {
+    // Accepts connections without ever responding
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let sink = HttpSink {
+      url: format!("http://{}/", listener.local_addr().unwrap()),
+      secret: None,
+      timeout: Duration::from_millis(100),
+    };
+    assert!(sink.deliver(&test_payload("rct_1")).await.is_err());
+
+    let sink = CommandSink {
+      command: "sleep 10".into(),
+      timeout: Duration::from_millis(100),
+    };
+    let started_at = std::time::Instant::now();
+    let error = sink.deliver(&test_payload("rct_1")).await.unwrap_err();
+    assert!(error.contains("timed out"));
+    assert!(started_at.elapsed() < Duration::from_secs(5));
+  }
+}
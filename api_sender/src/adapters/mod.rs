@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use protocol::receipt_builder::SCHEMA_VERSIONS;
+use serde_json::Value;
+
+pub mod stripe;
+
+/// Converts an order in some external format into a Versa receipt, so that senders can
+/// post their own order JSON to `/sender/send` as `{ "format": "...", "source": {...} }`.
+/// Converted receipts are schema validated like any other.
+pub trait ReceiptAdapter: Send + Sync {
+  /// Name of the format, matched against the `format` of a send request
+  fn format(&self) -> &str;
+
+  fn to_receipt(&self, source: &Value, schema_version: &str) -> Result<Value, String>;
+}
+
+/// Schema version for converted receipts that do not request one: the configured
+/// `VERSA_SCHEMA_VERSION` when the receipt builder can emit it, and otherwise the latest
+/// version it can
+pub fn default_schema_version() -> String {
+  let configured = crate::s_config::get_default_schema_version();
+  if SCHEMA_VERSIONS.contains(&configured.as_str()) {
+    return configured;
+  }
+  SCHEMA_VERSIONS
+    .last()
+    .map(|version| version.to_string())
+    .unwrap_or(configured)
+}
+
+/// The adapters available to a sender, keyed by format
+#[derive(Clone)]
+pub struct ReceiptAdapters {
+  adapters: HashMap<String, Arc<dyn ReceiptAdapter>>,
+}
+
+impl ReceiptAdapters {
+  pub fn empty() -> Self {
+    Self {
+      adapters: HashMap::new(),
+    }
+  }
+
+  /// Adds an adapter, replacing any existing adapter for the same format
+  pub fn with_adapter(mut self, adapter: impl ReceiptAdapter + 'static) -> Self {
+    self
+      .adapters
+      .insert(adapter.format().to_string(), Arc::new(adapter));
+    self
+  }
+
+  pub fn get(&self, format: &str) -> Option<&dyn ReceiptAdapter> {
+    self.adapters.get(format).map(|adapter| adapter.as_ref())
+  }
+
+  pub fn convert(
+    &self,
+    format: &str,
+    source: &Value,
+    schema_version: &str,
+  ) -> Result<Value, String> {
+    let Some(adapter) = self.get(format) else {
+      let mut formats: Vec<&str> = self.adapters.keys().map(String::as_str).collect();
+      formats.sort_unstable();
+      return Err(format!(
+        "Unknown receipt format: {}, expected one of: {}",
+        format,
+        formats.join(", ")
+      ));
+    };
+    adapter
+      .to_receipt(source, schema_version)
+      .map_err(|e| format!("Failed to convert {} source: {}", format, e))
+  }
+}
+
+impl Default for ReceiptAdapters {
+  /// The built-in adapters
+  fn default() -> Self {
+    Self::empty().with_adapter(stripe::StripeInvoiceAdapter)
+  }
+}
@@ -0,0 +1,202 @@
+use protocol::receipt_builder::{Adjustment, Customer, LineItem, Payment, ReceiptBuilder, Tax};
+use serde_json::Value;
+
+use super::ReceiptAdapter;
+
+/// Converts a Stripe-style invoice object, as returned by the Stripe API with amounts in
+/// minor currency units, into a Versa receipt. Line amounts and taxes are taken from the
+/// invoice, while the receipt's totals are computed from them. The rates of line item
+/// `tax_amounts` and the card of the invoice `charge` are used when those are expanded.
+pub struct StripeInvoiceAdapter;
+
+fn get_i64(value: &Value, key: &str) -> Option<i64> {
+  value.get(key).and_then(Value::as_i64)
+}
+
+fn get_str<'a>(value: &'a Value, key: &str) -> Option<&'a str> {
+  value.get(key).and_then(Value::as_str)
+}
+
+fn array<'a>(value: &'a Value, key: &str) -> impl Iterator<Item = &'a Value> {
+  value
+    .get(key)
+    .and_then(Value::as_array)
+    .map(|items| items.iter())
+    .into_iter()
+    .flatten()
+}
+
+fn line_item(line: &Value) -> LineItem {
+  let quantity = get_i64(line, "quantity").unwrap_or(1).max(1);
+  let amount = get_i64(line, "amount").unwrap_or(0);
+  let unit_cost = line
+    .get("price")
+    .and_then(|price| get_i64(price, "unit_amount"))
+    .unwrap_or(amount / quantity);
+
+  let mut item = LineItem::new(
+    get_str(line, "description").unwrap_or_default(),
+    quantity as f64,
+    unit_cost,
+  )
+  .subtotal(amount);
+
+  for tax in array(line, "tax_amounts") {
+    let amount = get_i64(tax, "amount").unwrap_or(0);
+    let rate = tax.get("tax_rate").filter(|rate| rate.is_object());
+    let name = rate
+      .and_then(|rate| get_str(rate, "display_name"))
+      .unwrap_or("Tax");
+    let percentage = rate
+      .and_then(|rate| rate.get("percentage"))
+      .and_then(Value::as_f64);
+    item = item.tax(match percentage {
+      Some(percentage) => Tax::fixed_at_rate(name, amount, percentage / 100.0),
+      None => Tax::fixed(name, amount),
+    });
+  }
+
+  for discount in array(line, "discount_amounts") {
+    match get_i64(discount, "amount") {
+      Some(amount) if amount != 0 => {
+        item = item.adjustment(Adjustment::discount("Discount", amount))
+      }
+      _ => {}
+    }
+  }
+
+  item
+}
+
+fn customer(invoice: &Value) -> Option<Customer> {
+  let name = get_str(invoice, "customer_name");
+  let email = get_str(invoice, "customer_email");
+  if name.is_none() && email.is_none() {
+    return None;
+  }
+  Some(Customer {
+    name: name.unwrap_or_default().to_string(),
+    email: email.map(String::from),
+    phone: get_str(invoice, "customer_phone").map(String::from),
+    website: None,
+  })
+}
+
+fn payment(invoice: &Value) -> Option<Payment> {
+  let amount_paid = get_i64(invoice, "amount_paid").unwrap_or(0);
+  if amount_paid == 0 {
+    return None;
+  }
+
+  let paid_at = invoice
+    .get("status_transitions")
+    .and_then(|transitions| get_i64(transitions, "paid_at"))
+    .or_else(|| get_i64(invoice, "created"))
+    .unwrap_or_default();
+
+  let card = invoice
+    .get("charge")
+    .and_then(|charge| charge.get("payment_method_details"))
+    .and_then(|details| details.get("card"));
+  Some(
+    match card.and_then(|card| Some((get_str(card, "last4")?, get_str(card, "brand")?))) {
+      Some((last_four, network)) => Payment::card(amount_paid, paid_at, last_four, network),
+      None => Payment::unknown_card(amount_paid, paid_at),
+    },
+  )
+}
+
+impl ReceiptAdapter for StripeInvoiceAdapter {
+  fn format(&self) -> &str {
+    "stripe_invoice"
+  }
+
+  fn to_receipt(&self, source: &Value, schema_version: &str) -> Result<Value, String> {
+    if get_str(source, "object").is_some_and(|object| object != "invoice") {
+      return Err("Expected a Stripe invoice object".to_string());
+    }
+    let currency = get_str(source, "currency").ok_or("Missing currency")?;
+    let created = get_i64(source, "created").ok_or("Missing created")?;
+
+    let mut builder = ReceiptBuilder::new(currency, created);
+    if let Some(number) = get_str(source, "number").or_else(|| get_str(source, "id")) {
+      builder = builder.invoice_number(number);
+    }
+    if let Some(customer) = customer(source) {
+      builder = builder.customer(customer);
+    }
+    if let Some(lines) = source.get("lines") {
+      for line in array(lines, "data") {
+        builder = builder.line_item(line_item(line));
+      }
+    }
+    if let Some(payment) = payment(source) {
+      builder = builder.payment(payment);
+    }
+    if let Some(url) = get_str(source, "hosted_invoice_url") {
+      builder = builder.action("View invoice", url);
+    }
+    if let Some(footer) = get_str(source, "footer") {
+      builder = builder.supplemental_text(footer);
+    }
+
+    builder.build(schema_version)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use pretty_assertions::assert_eq;
+  use serde_json::json;
+
+  #[test]
+  fn test_stripe_invoice_should_convert_to_receipt() {
+    let invoice = json!({
+      "object": "invoice",
+      "id": "in_123",
+      "number": "ABC-0001",
+      "currency": "USD",
+      "created": 1718000000,
+      "subtotal": 2000,
+      "total": 2160,
+      "amount_paid": 2160,
+      "customer_email": "jane@example.com",
+      "customer_name": "Jane Doe",
+      "status_transitions": { "paid_at": 1718000100 },
+      "lines": {
+        "data": [{
+          "description": "Widget",
+          "amount": 2000,
+          "quantity": 2,
+          "price": { "unit_amount": 1000 },
+          "tax_amounts": [{
+            "amount": 160,
+            "tax_rate": { "display_name": "Sales Tax", "percentage": 8.0 }
+          }]
+        }]
+      },
+      "charge": {
+        "payment_method_details": { "card": { "last4": "4242", "brand": "visa" } }
+      }
+    });
+
+    let receipt = StripeInvoiceAdapter.to_receipt(&invoice, "2.0.0").unwrap();
+
+    assert_eq!(receipt["header"]["invoice_number"], "ABC-0001");
+    assert_eq!(receipt["header"]["currency"], "usd");
+    assert_eq!(receipt["header"]["paid"], 2160);
+    assert_eq!(receipt["header"]["total"], 2160);
+    let line_item = &receipt["itemization"]["general"]["items"][0];
+    assert_eq!(line_item["unit_cost"], 1000);
+    assert_eq!(line_item["taxes"][0]["rate"], 0.08);
+    assert_eq!(receipt["payments"][0]["paid_at"], 1718000100);
+    assert_eq!(receipt["payments"][0]["card_payment"]["last_four"], "4242");
+
+    assert!(StripeInvoiceAdapter
+      .to_receipt(&json!({ "object": "charge" }), "2.0.0")
+      .is_err());
+    assert!(StripeInvoiceAdapter.to_receipt(&invoice, "1.10.0").is_err());
+  }
+}
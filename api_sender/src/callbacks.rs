@@ -0,0 +1,403 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use tracing::info;
+
+use crate::outbox::FailureOutcome;
+use crate::routes::ReceiverDeliveryResult;
+
+const WORKER_BATCH_SIZE: usize = 50;
+
+/// Outcome of a delivery attempt to one receiver, as reported to the delivery callback
+#[derive(Clone, Debug, Serialize)]
+pub struct ReceiverOutcome {
+  #[serde(flatten)]
+  pub result: ReceiverDeliveryResult,
+  /// 1-based attempt number of this outcome
+  pub attempt: u32,
+  /// When the outbox will retry a failed delivery
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub next_attempt_at: Option<i64>,
+  /// Whether a failed delivery exhausted its attempts and was moved to dead letters
+  pub dead_lettered: bool,
+}
+
+impl ReceiverOutcome {
+  pub fn new(
+    result: ReceiverDeliveryResult,
+    attempt: u32,
+    failure: Option<&FailureOutcome>,
+  ) -> Self {
+    let (next_attempt_at, dead_lettered) = match failure {
+      Some(FailureOutcome::Retry { next_attempt_at }) => (Some(*next_attempt_at), false),
+      Some(FailureOutcome::DeadLettered) => (None, true),
+      None => (None, false),
+    };
+    Self {
+      result,
+      attempt,
+      next_attempt_at,
+      dead_lettered,
+    }
+  }
+}
+
+/// Body of the delivery callback, sent once per receipt after the initial fan-out with
+/// every receiver, and again for each receiver whenever the outbox retries it
+#[derive(Debug, Serialize)]
+pub struct DeliveryCallback {
+  pub receipt_id: String,
+  pub transaction_id: Option<String>,
+  pub receivers: Vec<ReceiverOutcome>,
+}
+
+/// Durable queue of delivery callbacks that have not been acknowledged yet. Callbacks are
+/// written before they are first posted, retried with exponential backoff while the
+/// callback URL fails, deleted once acknowledged, and dropped after the configured
+/// attempts are exhausted.
+///
+/// Like outbox deliveries, each attempt holds a lease on its row so that the worker never
+/// posts a callback that is still being attempted.
+pub struct CallbackQueue {
+  conn: Mutex<Connection>,
+  max_attempts: u32,
+  retry_base_secs: i64,
+  lease_secs: i64,
+}
+
+/// A queued callback body, as read back for an attempt
+#[derive(Debug)]
+pub struct PendingCallback {
+  pub id: i64,
+  pub receipt_id: String,
+  pub body: String,
+  pub attempts: u32,
+}
+
+impl CallbackQueue {
+  pub fn open(
+    path: &str,
+    max_attempts: u32,
+    retry_base_secs: i64,
+    lease_secs: i64,
+  ) -> rusqlite::Result<Self> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(
+      "CREATE TABLE IF NOT EXISTS delivery_callbacks (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        receipt_id TEXT NOT NULL,
+        body TEXT NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at INTEGER NOT NULL,
+        last_error TEXT,
+        created_at INTEGER NOT NULL
+      );
+      CREATE INDEX IF NOT EXISTS delivery_callbacks_due
+        ON delivery_callbacks (next_attempt_at);",
+    )?;
+    Ok(Self {
+      conn: Mutex::new(conn),
+      max_attempts,
+      retry_base_secs,
+      lease_secs,
+    })
+  }
+
+  /// Persists a callback ahead of its first attempt, leased to the caller
+  pub fn enqueue(&self, callback: &DeliveryCallback) -> rusqlite::Result<PendingCallback> {
+    let now = util::unix_timestamp();
+    let body = serde_json::to_string(callback)
+      .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    let conn = self.conn.lock().unwrap();
+    conn.execute(
+      "INSERT INTO delivery_callbacks (receipt_id, body, next_attempt_at, created_at)
+      VALUES (?1, ?2, ?3, ?4)",
+      params![callback.receipt_id, body, now + self.lease_secs, now],
+    )?;
+    Ok(PendingCallback {
+      id: conn.last_insert_rowid(),
+      receipt_id: callback.receipt_id.clone(),
+      body,
+      attempts: 0,
+    })
+  }
+
+  pub fn mark_sent(&self, id: i64) -> rusqlite::Result<()> {
+    let conn = self.conn.lock().unwrap();
+    conn.execute("DELETE FROM delivery_callbacks WHERE id = ?1", params![id])?;
+    Ok(())
+  }
+
+  /// Records a failed attempt, scheduling the next one with exponential backoff or dropping
+  /// the callback once `max_attempts` is reached. Returns when the next attempt is due.
+  pub fn record_failure(&self, id: i64, error: &str) -> rusqlite::Result<Option<i64>> {
+    let now = util::unix_timestamp();
+    let mut conn = self.conn.lock().unwrap();
+    let tx = conn.transaction()?;
+
+    let attempts: u32 = tx.query_row(
+      "SELECT attempts FROM delivery_callbacks WHERE id = ?1",
+      params![id],
+      |row| row.get(0),
+    )?;
+    let attempts = attempts + 1;
+
+    let next_attempt_at = if attempts >= self.max_attempts {
+      tx.execute("DELETE FROM delivery_callbacks WHERE id = ?1", params![id])?;
+      None
+    } else {
+      let next_attempt_at = now + util::retry_delay(self.retry_base_secs, attempts);
+      tx.execute(
+        "UPDATE delivery_callbacks SET attempts = ?2, last_error = ?3, next_attempt_at = ?4
+        WHERE id = ?1",
+        params![id, attempts, error, next_attempt_at],
+      )?;
+      Some(next_attempt_at)
+    };
+
+    tx.commit()?;
+    Ok(next_attempt_at)
+  }
+
+  /// Leases up to `limit` callbacks whose backoff or lease has elapsed
+  pub fn claim_due(&self, now: i64, limit: usize) -> rusqlite::Result<Vec<PendingCallback>> {
+    let mut conn = self.conn.lock().unwrap();
+    let tx = conn.transaction()?;
+
+    let callbacks = {
+      let mut stmt = tx.prepare(
+        "SELECT id, receipt_id, body, attempts FROM delivery_callbacks
+        WHERE next_attempt_at <= ?1 ORDER BY next_attempt_at LIMIT ?2",
+      )?;
+      let rows = stmt.query_map(params![now, limit as i64], |row| {
+        Ok(PendingCallback {
+          id: row.get(0)?,
+          receipt_id: row.get(1)?,
+          body: row.get(2)?,
+          attempts: row.get(3)?,
+        })
+      })?;
+      rows.collect::<rusqlite::Result<Vec<_>>>()?
+    };
+
+    for callback in &callbacks {
+      tx.execute(
+        "UPDATE delivery_callbacks SET next_attempt_at = ?2 WHERE id = ?1",
+        params![callback.id, now + self.lease_secs],
+      )?;
+    }
+
+    tx.commit()?;
+    Ok(callbacks)
+  }
+}
+
+/// Queues the callback for the configured `VERSA_DELIVERY_CALLBACK_URL` and makes its first
+/// attempt in the background. Does nothing when no callback URL is configured.
+pub fn notify(queue: &Arc<CallbackQueue>, callback: DeliveryCallback) {
+  if crate::s_config::get_delivery_callback_url().is_none() {
+    return;
+  }
+  let pending = match queue.enqueue(&callback) {
+    Ok(val) => val,
+    Err(e) => {
+      info!(
+        "Failed to queue delivery callback for receipt {}: {:?}",
+        callback.receipt_id, e
+      );
+      return;
+    }
+  };
+  let queue = queue.clone();
+  tokio::spawn(async move { attempt(&queue, pending).await });
+}
+
+/// Periodically retries callbacks whose backoff or lease has elapsed
+pub async fn run_worker(queue: Arc<CallbackQueue>) {
+  let poll_interval = Duration::from_secs(crate::s_config::get_delivery_callback_poll_secs());
+  loop {
+    tokio::time::sleep(poll_interval).await;
+    if crate::s_config::get_delivery_callback_url().is_none() {
+      continue;
+    }
+
+    let due = match queue.claim_due(util::unix_timestamp(), WORKER_BATCH_SIZE) {
+      Ok(val) => val,
+      Err(e) => {
+        info!("Failed to read due delivery callbacks: {:?}", e);
+        continue;
+      }
+    };
+    for callback in due {
+      attempt(&queue, callback).await;
+    }
+  }
+}
+
+async fn attempt(queue: &CallbackQueue, callback: PendingCallback) {
+  let recorded = match post_callback(&callback.body).await {
+    Ok(()) => {
+      info!("Sent delivery callback for receipt {}", callback.receipt_id);
+      queue.mark_sent(callback.id)
+    }
+    Err(e) => {
+      info!(
+        "Failed to send delivery callback for receipt {} (attempt {}): {}",
+        callback.receipt_id,
+        callback.attempts + 1,
+        e
+      );
+      queue
+        .record_failure(callback.id, &e)
+        .map(|next_attempt_at| {
+          if next_attempt_at.is_none() {
+            info!(
+              "Dropping delivery callback for receipt {} after {} attempts",
+              callback.receipt_id,
+              callback.attempts + 1
+            );
+          }
+        })
+    }
+  };
+  if let Err(e) = recorded {
+    info!("Failed to record delivery callback outcome: {:?}", e);
+  }
+}
+
+/// The `X-Request-Signature` header for a callback body, the HMAC of the body alone like
+/// webhooks sent by the `versa` client. With a `timestamp`, the signature instead covers
+/// `{timestamp}.{body}` and the timestamp is sent in `X-Request-Timestamp`.
+async fn signature_headers(
+  body: &[u8],
+  secret: &str,
+  timestamp: Option<i64>,
+) -> Vec<(&'static str, String)> {
+  match timestamp {
+    Some(timestamp) => vec![
+      ("X-Request-Timestamp", timestamp.to_string()),
+      (
+        "X-Request-Signature",
+        protocol::hmac_util::generate_timestamped_token(timestamp, body, secret),
+      ),
+    ],
+    None => vec![(
+      "X-Request-Signature",
+      protocol::hmac_util::generate_token(bytes::Bytes::copy_from_slice(body), secret.to_string())
+        .await,
+    )],
+  }
+}
+
+async fn post_callback(body: &str) -> Result<(), String> {
+  let url = crate::s_config::get_delivery_callback_url()
+    .ok_or_else(|| "No delivery callback URL is configured".to_string())?;
+  let secret = crate::s_config::get_delivery_callback_secret()
+    .ok_or_else(|| "No delivery callback secret is configured".to_string())?;
+
+  let mut request = reqwest::Client::new()
+    .post(&url)
+    .timeout(Duration::from_secs(
+      crate::s_config::get_delivery_callback_timeout_secs(),
+    ))
+    .header("Content-Type", "application/json");
+  let timestamp = crate::s_config::get_delivery_callback_timestamped().then(util::unix_timestamp);
+  for (name, value) in signature_headers(body.as_bytes(), &secret, timestamp).await {
+    request = request.header(name, value);
+  }
+
+  let res = request
+    .body(body.to_string())
+    .send()
+    .await
+    .map_err(|e| format!("{:?}", e))?;
+  if !res.status().is_success() {
+    return Err(format!("Received status {}", res.status()));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  fn callback() -> DeliveryCallback {
+    DeliveryCallback {
+      receipt_id: "rct_123".into(),
+      transaction_id: Some("txn_123".into()),
+      receivers: vec![],
+    }
+  }
+
+  #[test]
+  fn test_failed_callback_should_back_off_and_be_dropped_after_max_attempts() {
+    let queue = CallbackQueue::open(":memory:", 2, 30, 60).unwrap();
+    let pending = queue.enqueue(&callback()).unwrap();
+    let now = util::unix_timestamp();
+
+    // Leased to the first attempt
+    assert!(queue.claim_due(now, 10).unwrap().is_empty());
+
+    let next_attempt_at = queue
+      .record_failure(pending.id, "Received status 500")
+      .unwrap();
+    assert!(next_attempt_at.unwrap() >= now + 30);
+
+    let due = queue.claim_due(now + 3600, 10).unwrap();
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].attempts, 1);
+    assert_eq!(due[0].receipt_id, "rct_123");
+    assert!(due[0].body.contains("\"txn_123\""));
+
+    assert_eq!(
+      queue
+        .record_failure(pending.id, "Received status 500")
+        .unwrap(),
+      None
+    );
+    assert!(queue.claim_due(now + 7200, 10).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_sent_callback_should_be_deleted() {
+    let queue = CallbackQueue::open(":memory:", 5, 30, 0).unwrap();
+    let pending = queue.enqueue(&callback()).unwrap();
+    queue.mark_sent(pending.id).unwrap();
+    assert!(queue
+      .claim_due(util::unix_timestamp() + 3600, 10)
+      .unwrap()
+      .is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_signature_should_cover_body_and_timestamp_when_timestamped() {
+    let body = br#"{"receipt_id":"rct_123"}"#;
+
+    let headers = signature_headers(body, "secret", None).await;
+    assert_eq!(
+      headers,
+      vec![(
+        "X-Request-Signature",
+        protocol::hmac_util::generate_token(bytes::Bytes::from_static(body), "secret".into()).await
+      )]
+    );
+
+    let headers = signature_headers(body, "secret", Some(1000)).await;
+    let [(_, timestamp), (_, signature)] = &headers[..] else {
+      panic!("Expected timestamp and signature headers");
+    };
+    assert_eq!(timestamp, "1000");
+    assert_eq!(
+      protocol::hmac_util::verify_timestamped_token(body, "1000", signature, "secret", 300, 1000),
+      Ok(())
+    );
+    assert!(protocol::hmac_util::verify_timestamped_token(
+      body, "1001", signature, "secret", 300, 1000
+    )
+    .is_err());
+  }
+}
@@ -0,0 +1,225 @@
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::Value;
+
+/// Remembers the first response returned for each idempotency key so that retried
+/// requests are answered from the store instead of registering the receipt again.
+///
+/// Each key is bound to a fingerprint of the request that first used it. A request that is
+/// still running holds its key for `lease_secs`, after which the key can be taken over by
+/// a retry, so that a request that never finished does not block its key for the window.
+pub struct IdempotencyStore {
+  conn: Mutex<Connection>,
+  window_secs: i64,
+  lease_secs: i64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum IdempotencyState {
+  /// The key was not seen within the window, or its previous holder's lease expired, and
+  /// it is now reserved for this request
+  Started,
+  /// Another request with the same key has not finished yet
+  InProgress,
+  /// A previous request with the same key completed with this status and body
+  Completed { status: u16, response: Value },
+  /// The key was first used for a request with a different fingerprint
+  Mismatch,
+}
+
+impl IdempotencyStore {
+  pub fn open(path: &str, window_secs: i64, lease_secs: i64) -> rusqlite::Result<Self> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(
+      "CREATE TABLE IF NOT EXISTS idempotency_keys (
+        key TEXT PRIMARY KEY,
+        fingerprint TEXT NOT NULL,
+        status INTEGER,
+        response TEXT,
+        lease_until INTEGER,
+        created_at INTEGER NOT NULL
+      );",
+    )?;
+    Ok(Self {
+      conn: Mutex::new(conn),
+      window_secs,
+      lease_secs,
+    })
+  }
+
+  pub fn begin(&self, key: &str, fingerprint: &str) -> rusqlite::Result<IdempotencyState> {
+    let now = util::unix_timestamp();
+    let mut conn = self.conn.lock().unwrap();
+    let tx = conn.transaction()?;
+
+    tx.execute(
+      "DELETE FROM idempotency_keys WHERE created_at <= ?1",
+      params![now - self.window_secs],
+    )?;
+
+    let existing = tx
+      .query_row(
+        "SELECT fingerprint, status, response, lease_until FROM idempotency_keys
+        WHERE key = ?1",
+        params![key],
+        |row| {
+          Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, Option<u16>>(1)?,
+            row.get::<_, Option<String>>(2)?,
+            row.get::<_, Option<i64>>(3)?,
+          ))
+        },
+      )
+      .optional()?;
+
+    let state = match existing {
+      Some((existing, ..)) if existing != fingerprint => IdempotencyState::Mismatch,
+      Some((_, Some(status), Some(response), _)) => IdempotencyState::Completed {
+        status,
+        response: serde_json::from_str(&response).unwrap_or(Value::Null),
+      },
+      Some((_, _, _, Some(lease_until))) if lease_until > now => IdempotencyState::InProgress,
+      Some(_) => {
+        tx.execute(
+          "UPDATE idempotency_keys SET lease_until = ?2 WHERE key = ?1",
+          params![key, now + self.lease_secs],
+        )?;
+        IdempotencyState::Started
+      }
+      None => {
+        tx.execute(
+          "INSERT INTO idempotency_keys (key, fingerprint, lease_until, created_at)
+          VALUES (?1, ?2, ?3, ?4)",
+          params![key, fingerprint, now + self.lease_secs, now],
+        )?;
+        IdempotencyState::Started
+      }
+    };
+
+    tx.commit()?;
+    Ok(state)
+  }
+
+  pub fn complete(&self, key: &str, status: u16, response: &Value) -> rusqlite::Result<()> {
+    let conn = self.conn.lock().unwrap();
+    conn.execute(
+      "UPDATE idempotency_keys SET status = ?2, response = ?3, lease_until = NULL
+      WHERE key = ?1",
+      params![key, status, response.to_string()],
+    )?;
+    Ok(())
+  }
+
+  /// Forgets a reserved key after a failure that did not register anything, so the
+  /// request can be retried with the same key
+  pub fn release(&self, key: &str) -> rusqlite::Result<()> {
+    let conn = self.conn.lock().unwrap();
+    conn.execute(
+      "DELETE FROM idempotency_keys WHERE key = ?1 AND status IS NULL",
+      params![key],
+    )?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  #[test]
+  fn test_completed_key_should_replay_response() {
+    let store = IdempotencyStore::open(":memory:", 3600, 60).unwrap();
+    let response = serde_json::json!({ "receipt_id": "rct_123" });
+
+    assert_eq!(
+      store.begin("key_1", "fp_1").unwrap(),
+      IdempotencyState::Started
+    );
+    assert_eq!(
+      store.begin("key_1", "fp_1").unwrap(),
+      IdempotencyState::InProgress
+    );
+
+    store.complete("key_1", 200, &response).unwrap();
+    assert_eq!(
+      store.begin("key_1", "fp_1").unwrap(),
+      IdempotencyState::Completed {
+        status: 200,
+        response
+      }
+    );
+  }
+
+  #[test]
+  fn test_released_key_should_start_again() {
+    let store = IdempotencyStore::open(":memory:", 3600, 60).unwrap();
+
+    assert_eq!(
+      store.begin("key_1", "fp_1").unwrap(),
+      IdempotencyState::Started
+    );
+    store.release("key_1").unwrap();
+    assert_eq!(
+      store.begin("key_1", "fp_1").unwrap(),
+      IdempotencyState::Started
+    );
+  }
+
+  #[test]
+  fn test_expired_key_should_start_again() {
+    let store = IdempotencyStore::open(":memory:", 0, 60).unwrap();
+
+    assert_eq!(
+      store.begin("key_1", "fp_1").unwrap(),
+      IdempotencyState::Started
+    );
+    store
+      .complete("key_1", 200, &serde_json::json!({}))
+      .unwrap();
+    assert_eq!(
+      store.begin("key_1", "fp_1").unwrap(),
+      IdempotencyState::Started
+    );
+  }
+
+  #[test]
+  fn test_abandoned_key_should_start_again_once_its_lease_expires() {
+    let store = IdempotencyStore::open(":memory:", 3600, 0).unwrap();
+
+    // The first request never completed nor released its key
+    assert_eq!(
+      store.begin("key_1", "fp_1").unwrap(),
+      IdempotencyState::Started
+    );
+    assert_eq!(
+      store.begin("key_1", "fp_1").unwrap(),
+      IdempotencyState::Started
+    );
+  }
+
+  #[test]
+  fn test_key_reused_for_different_request_should_mismatch() {
+    let store = IdempotencyStore::open(":memory:", 3600, 60).unwrap();
+
+    assert_eq!(
+      store.begin("key_1", "fp_1").unwrap(),
+      IdempotencyState::Started
+    );
+    assert_eq!(
+      store.begin("key_1", "fp_2").unwrap(),
+      IdempotencyState::Mismatch
+    );
+
+    store
+      .complete("key_1", 200, &serde_json::json!({}))
+      .unwrap();
+    assert_eq!(
+      store.begin("key_1", "fp_2").unwrap(),
+      IdempotencyState::Mismatch
+    );
+  }
+}
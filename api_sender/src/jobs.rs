@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::Semaphore;
+use tracing::info;
+
+use crate::dispatch::SendProgress;
+use crate::routes::{ReceiverDeliveryResult, SendReceiptResponse, SendRequestPayload};
+use crate::SenderState;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+  Queued,
+  Registering,
+  Delivering,
+  Completed,
+  Failed,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Job {
+  pub id: String,
+  pub status: JobStatus,
+  pub receipt_id: Option<String>,
+  pub transaction_id: Option<String>,
+  pub receivers_total: Option<usize>,
+  pub receivers: Vec<ReceiverDeliveryResult>,
+  pub error: Option<String>,
+  pub created_at: i64,
+  pub updated_at: i64,
+}
+
+/// In-process queue of asynchronous sends. Jobs run on the tokio runtime, at most
+/// `max_concurrent` at a time, and are kept in memory for `retention_secs` after they
+/// finish so their status can be polled.
+///
+/// Jobs are not persisted: a restart loses queued jobs and the status of every job. The
+/// deliveries of a job that got as far as registering its receipt are in the outbox,
+/// which retries them after a restart.
+pub struct JobQueue {
+  jobs: Mutex<HashMap<String, Job>>,
+  permits: Arc<Semaphore>,
+  retention_secs: i64,
+}
+
+impl JobQueue {
+  pub fn new(max_concurrent: usize, retention_secs: i64) -> Self {
+    Self {
+      jobs: Mutex::new(HashMap::new()),
+      permits: Arc::new(Semaphore::new(max_concurrent.max(1))),
+      retention_secs,
+    }
+  }
+
+  pub fn get(&self, id: &str) -> Option<Job> {
+    self.jobs.lock().unwrap().get(id).cloned()
+  }
+
+  pub fn submit(&self, state: SenderState, payload: SendRequestPayload, receipt: Value) -> String {
+    let id = self.create();
+    let permits = self.permits.clone();
+    let job_id = id.clone();
+    tokio::spawn(async move {
+      let _permit = permits.acquire_owned().await.unwrap();
+      run(state, job_id, payload, receipt).await;
+    });
+
+    id
+  }
+
+  /// Adds a queued job, dropping finished jobs older than the retention period
+  fn create(&self) -> String {
+    let now = util::unix_timestamp();
+    let id = format!("job_{:032x}", rand::random::<u128>());
+    let job = Job {
+      id: id.clone(),
+      status: JobStatus::Queued,
+      receipt_id: None,
+      transaction_id: None,
+      receivers_total: None,
+      receivers: Vec::new(),
+      error: None,
+      created_at: now,
+      updated_at: now,
+    };
+
+    {
+      let mut jobs = self.jobs.lock().unwrap();
+      jobs.retain(|_, job| !job.is_finished() || job.updated_at + self.retention_secs > now);
+      jobs.insert(id.clone(), job);
+    }
+    id
+  }
+
+  fn update<F: FnOnce(&mut Job)>(&self, id: &str, f: F) {
+    if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+      f(job);
+      job.updated_at = util::unix_timestamp();
+    }
+  }
+}
+
+impl Job {
+  fn is_finished(&self) -> bool {
+    matches!(self.status, JobStatus::Completed | JobStatus::Failed)
+  }
+}
+
+struct JobProgress<'a> {
+  queue: &'a JobQueue,
+  id: &'a str,
+}
+
+impl SendProgress for JobProgress<'_> {
+  fn registered(&self, receipt_id: &str, transaction_id: &str, receivers: usize) {
+    self.queue.update(self.id, |job| {
+      job.status = JobStatus::Delivering;
+      job.receipt_id = Some(receipt_id.to_string());
+      job.transaction_id = Some(transaction_id.to_string());
+      job.receivers_total = Some(receivers);
+    });
+  }
+
+  fn delivered(&self, result: &ReceiverDeliveryResult) {
+    self
+      .queue
+      .update(self.id, |job| job.receivers.push(result.clone()));
+  }
+}
+
+async fn run(state: SenderState, id: String, payload: SendRequestPayload, receipt: Value) {
+  let queue = state.jobs.clone();
+  queue.update(&id, |job| job.status = JobStatus::Registering);
+
+  let progress = JobProgress {
+    queue: &queue,
+    id: &id,
+  };
+  match crate::dispatch::send_receipt(&state, payload, receipt, &progress).await {
+    Ok(SendReceiptResponse { receivers, .. }) => {
+      info!("Job {} completed", id);
+      queue.update(&id, |job| {
+        job.status = JobStatus::Completed;
+        job.receivers = receivers;
+      });
+    }
+    Err((status, message)) => {
+      info!("Job {} failed with status {}: {}", id, status, message);
+      queue.update(&id, |job| {
+        job.status = JobStatus::Failed;
+        job.error = Some(message);
+      });
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  #[test]
+  fn test_zero_concurrent_jobs_should_still_run_one() {
+    let queue = JobQueue::new(0, 3600);
+    assert_eq!(queue.permits.available_permits(), 1);
+  }
+
+  #[test]
+  fn test_progress_should_be_reported_on_the_job() {
+    let queue = JobQueue::new(4, 3600);
+    let id = queue.create();
+    assert_eq!(queue.get(&id).unwrap().status, JobStatus::Queued);
+    assert!(queue.get("job_unknown").is_none());
+
+    let progress = JobProgress {
+      queue: &queue,
+      id: &id,
+    };
+    progress.registered("rct_123", "txn_123", 2);
+    progress.delivered(&ReceiverDeliveryResult {
+      org_id: "org_456".into(),
+      address: "https://receiver.example.com/webhook".into(),
+      status: crate::routes::DeliveryStatus::Delivered,
+      error: None,
+      latency_ms: 12,
+    });
+
+    let job = queue.get(&id).unwrap();
+    assert_eq!(job.status, JobStatus::Delivering);
+    assert_eq!(job.receipt_id.as_deref(), Some("rct_123"));
+    assert_eq!(job.transaction_id.as_deref(), Some("txn_123"));
+    assert_eq!(job.receivers_total, Some(2));
+    assert_eq!(job.receivers.len(), 1);
+  }
+
+  #[test]
+  fn test_finished_jobs_should_be_dropped_after_retention() {
+    let queue = JobQueue::new(4, 60);
+    let finished = queue.create();
+    let running = queue.create();
+    queue.update(&finished, |job| job.status = JobStatus::Completed);
+
+    // Age both jobs past the retention period
+    for job in queue.jobs.lock().unwrap().values_mut() {
+      job.updated_at -= 61;
+    }
+    queue.create();
+
+    assert!(queue.get(&finished).is_none());
+    assert_eq!(queue.get(&running).unwrap().status, JobStatus::Queued);
+  }
+}
@@ -1,12 +1,145 @@
-use axum::routing::{delete, post};
+use std::sync::Arc;
+
+use axum::extract::DefaultBodyLimit;
+use axum::routing::{delete, get, post};
 use axum::Router;
 
+pub mod adapters;
+pub mod callbacks;
+pub mod dispatch;
+pub mod idempotency;
+pub mod jobs;
+pub mod outbox;
 pub mod routes;
 
+mod s_config;
+
+#[derive(Clone)]
+pub struct SenderState {
+  pub outbox: Arc<outbox::Outbox>,
+  pub callbacks: Arc<callbacks::CallbackQueue>,
+  pub jobs: Arc<jobs::JobQueue>,
+  pub idempotency: Arc<idempotency::IdempotencyStore>,
+  pub customers: Arc<protocol::customer_ledger::CustomerLedger>,
+  pub adapters: adapters::ReceiptAdapters,
+}
+
+/// Opens the ledger of customers registered by this sender
+pub fn open_customer_ledger() -> protocol::customer_ledger::CustomerLedger {
+  protocol::customer_ledger::CustomerLedger::open(&s_config::get_customer_ledger_path())
+    .expect("Failed to open customer ledger")
+}
+
+/// State backed by in-memory stores, for tests of the route handlers
+#[cfg(test)]
+pub(crate) fn test_state() -> SenderState {
+  SenderState {
+    outbox: Arc::new(outbox::Outbox::open(":memory:", 8, 30, 60, 86_400).unwrap()),
+    callbacks: Arc::new(callbacks::CallbackQueue::open(":memory:", 8, 30, 60).unwrap()),
+    jobs: Arc::new(jobs::JobQueue::new(4, 3600)),
+    idempotency: Arc::new(idempotency::IdempotencyStore::open(":memory:", 86_400, 120).unwrap()),
+    customers: Arc::new(protocol::customer_ledger::CustomerLedger::open(":memory:").unwrap()),
+    adapters: adapters::ReceiptAdapters::default(),
+  }
+}
+
 pub fn configure() -> Router {
+  configure_with_adapters(adapters::ReceiptAdapters::default())
+}
+
+/// Same as [`configure`], accepting `format`/`source` send requests in the formats of
+/// the given receipt adapters
+pub fn configure_with_adapters(adapters: adapters::ReceiptAdapters) -> Router {
+  if s_config::get_delivery_callback_url().is_some()
+    && s_config::get_delivery_callback_secret().is_none()
+  {
+    panic!("VERSA_DELIVERY_CALLBACK_SECRET must be set when VERSA_DELIVERY_CALLBACK_URL is");
+  }
+
+  // An attempt must time out before its lease lets the worker make it again
+  if s_config::get_delivery_timeout_secs() as i64 >= s_config::get_outbox_lease_secs() {
+    panic!("VERSA_DELIVERY_TIMEOUT_SECS must be shorter than VERSA_OUTBOX_LEASE_SECS");
+  }
+  if s_config::get_delivery_callback_timeout_secs() as i64
+    >= s_config::get_delivery_callback_lease_secs()
+  {
+    panic!(
+      "VERSA_DELIVERY_CALLBACK_TIMEOUT_SECS must be shorter than VERSA_DELIVERY_CALLBACK_LEASE_SECS"
+    );
+  }
+
+  let schema_version = s_config::get_default_schema_version();
+  if !protocol::schema::is_supported_schema_version(&schema_version) {
+    panic!(
+      "VERSA_SCHEMA_VERSION {} is not supported, expected one of: {}",
+      schema_version,
+      protocol::schema::SUPPORTED_SCHEMA_VERSIONS.join(", ")
+    );
+  }
+
+  let outbox = outbox::Outbox::open(
+    &s_config::get_outbox_path(),
+    s_config::get_outbox_max_attempts(),
+    s_config::get_outbox_retry_base_secs(),
+    s_config::get_outbox_lease_secs(),
+    s_config::get_dead_letter_retention_secs(),
+  )
+  .expect("Failed to open sender outbox");
+  let outbox = Arc::new(outbox);
+
+  let callbacks = callbacks::CallbackQueue::open(
+    &s_config::get_delivery_callback_path(),
+    s_config::get_delivery_callback_max_attempts(),
+    s_config::get_delivery_callback_retry_base_secs(),
+    s_config::get_delivery_callback_lease_secs(),
+  )
+  .expect("Failed to open delivery callback queue");
+  let callbacks = Arc::new(callbacks);
+  tokio::spawn(outbox::run_worker(outbox.clone(), callbacks.clone()));
+  tokio::spawn(callbacks::run_worker(callbacks.clone()));
+
+  let jobs = Arc::new(jobs::JobQueue::new(
+    s_config::get_max_concurrent_jobs(),
+    s_config::get_job_retention_secs(),
+  ));
+
+  let idempotency = idempotency::IdempotencyStore::open(
+    &s_config::get_idempotency_path(),
+    s_config::get_idempotency_window_secs(),
+    s_config::get_idempotency_lease_secs(),
+  )
+  .expect("Failed to open idempotency store");
+
+  let state = SenderState {
+    outbox,
+    callbacks,
+    jobs,
+    idempotency: Arc::new(idempotency),
+    customers: Arc::new(open_customer_ledger()),
+    adapters,
+  };
+
   Router::new()
     .route("/customer", delete(routes::deregister_customer))
+    .route("/customer", get(routes::customers::list))
     .route("/customer", post(routes::register_customer))
+    .route("/customer/{handle}", get(routes::customers::lookup))
+    .route(
+      "/customer/import",
+      post(routes::customers::import)
+        .layer(DefaultBodyLimit::max(s_config::get_max_import_bytes())),
+    )
     .route("/check_registry", post(routes::check_registry))
     .route("/send", post(routes::send))
+    .route(
+      "/send_batch",
+      post(routes::batch::send_batch).layer(DefaultBodyLimit::max(s_config::get_max_batch_bytes())),
+    )
+    .route("/jobs/{id}", get(routes::jobs::get_job))
+    .route("/outbox/dead_letters", get(routes::outbox::dead_letters))
+    .route(
+      "/outbox/dead_letters/{id}/replay",
+      post(routes::outbox::replay_dead_letter),
+    )
+    .with_state(state)
 }
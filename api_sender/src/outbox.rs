@@ -0,0 +1,616 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use protocol::model::Envelope;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::info;
+use versa::protocol::webhook::{TransactionEvent, WebhookEvent};
+use versa::protocol::{
+  EncryptionKey, ReceiptRegistrationSummary, ReceiverInstruction, ReceiverPayload,
+};
+
+use crate::callbacks::{self, CallbackQueue, DeliveryCallback, ReceiverOutcome};
+use crate::routes::{DeliveryStatus, ReceiverDeliveryResult};
+
+const WORKER_BATCH_SIZE: usize = 50;
+
+/// Durable record of every pending (receipt, receiver) delivery. Rows are written before
+/// the first attempt so that a failed or interrupted delivery can be retried by the worker,
+/// are deleted once delivered, and are moved to the `dead_letters` table once the
+/// configured attempts are exhausted. Dead letters are purged after their retention.
+///
+/// Rows hold only what a retry posts: the receipt encrypted for the receiver, and the
+/// receiver's address and webhook secret to sign it with. Neither the plaintext receipt
+/// nor its encryption key are stored.
+///
+/// Each attempt holds a lease on its row: a row is `in_flight` until its lease expires, so
+/// the worker never picks up a delivery that is still being attempted.
+pub struct Outbox {
+  conn: Mutex<Connection>,
+  max_attempts: u32,
+  retry_base_secs: i64,
+  lease_secs: i64,
+  dead_letter_retention_secs: i64,
+}
+
+pub struct NewDelivery<'a> {
+  pub receiver: &'a ReceiverInstruction,
+  pub summary: &'a ReceiptRegistrationSummary,
+  pub encryption_key: &'a EncryptionKey,
+  pub receipt: &'a Value,
+}
+
+pub struct Delivery {
+  pub id: i64,
+  pub receipt_id: String,
+  pub transaction_id: String,
+  pub org_id: String,
+  pub address: String,
+  pub event_id: String,
+  pub event_type: String,
+  pub secret: String,
+  pub envelope: Envelope,
+  pub attempts: u32,
+  pub created_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeadLetter {
+  pub id: i64,
+  pub receipt_id: String,
+  pub org_id: String,
+  pub address: String,
+  pub attempts: u32,
+  pub last_error: Option<String>,
+  pub created_at: i64,
+  pub failed_at: i64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FailureOutcome {
+  Retry { next_attempt_at: i64 },
+  DeadLettered,
+}
+
+fn to_sql_error<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> rusqlite::Error {
+  rusqlite::Error::ToSqlConversionFailure(e.into())
+}
+
+impl Outbox {
+  pub fn open(
+    path: &str,
+    max_attempts: u32,
+    retry_base_secs: i64,
+    lease_secs: i64,
+    dead_letter_retention_secs: i64,
+  ) -> rusqlite::Result<Self> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(
+      "CREATE TABLE IF NOT EXISTS outbox (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        receipt_id TEXT NOT NULL,
+        transaction_id TEXT NOT NULL,
+        org_id TEXT NOT NULL,
+        address TEXT NOT NULL,
+        event_id TEXT NOT NULL,
+        event_type TEXT NOT NULL,
+        secret TEXT NOT NULL,
+        envelope TEXT NOT NULL,
+        status TEXT NOT NULL DEFAULT 'pending',
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at INTEGER NOT NULL,
+        last_error TEXT,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+      );
+      CREATE INDEX IF NOT EXISTS outbox_due ON outbox (next_attempt_at);
+      CREATE TABLE IF NOT EXISTS dead_letters (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        receipt_id TEXT NOT NULL,
+        transaction_id TEXT NOT NULL,
+        org_id TEXT NOT NULL,
+        address TEXT NOT NULL,
+        event_id TEXT NOT NULL,
+        event_type TEXT NOT NULL,
+        secret TEXT NOT NULL,
+        envelope TEXT NOT NULL,
+        attempts INTEGER NOT NULL,
+        last_error TEXT,
+        created_at INTEGER NOT NULL,
+        failed_at INTEGER NOT NULL
+      );
+      CREATE INDEX IF NOT EXISTS dead_letters_failed_at ON dead_letters (failed_at);",
+    )?;
+    Ok(Self {
+      conn: Mutex::new(conn),
+      max_attempts,
+      retry_base_secs,
+      lease_secs,
+      dead_letter_retention_secs,
+    })
+  }
+
+  /// Encrypts the receipt for the receiver and persists the delivery ahead of its first
+  /// attempt, leased to the caller so that the worker leaves it alone while the caller
+  /// makes that attempt.
+  pub fn enqueue(&self, delivery: &NewDelivery) -> rusqlite::Result<i64> {
+    let now = util::unix_timestamp();
+    let envelope =
+      protocol::encryption::encrypt_envelope(delivery.receipt, &delivery.encryption_key.0)
+        .map_err(to_sql_error)?;
+    let envelope = serde_json::to_string(&envelope).map_err(to_sql_error)?;
+    let conn = self.conn.lock().unwrap();
+    conn.execute(
+      "INSERT INTO outbox (receipt_id, transaction_id, org_id, address, event_id, event_type,
+        secret, envelope, status, next_attempt_at, created_at, updated_at)
+      VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 'in_flight', ?9, ?10, ?10)",
+      params![
+        delivery.summary.receipt_id,
+        delivery.summary.transaction_id,
+        delivery.receiver.org_id,
+        delivery.receiver.address,
+        delivery.receiver.event_id,
+        delivery.receiver.event_type.to_string(),
+        delivery.receiver.secret,
+        envelope,
+        now + self.lease_secs,
+        now,
+      ],
+    )?;
+    Ok(conn.last_insert_rowid())
+  }
+
+  /// Removes a delivered row, so that its envelope and secret do not outlive the delivery
+  pub fn mark_delivered(&self, id: i64) -> rusqlite::Result<()> {
+    let conn = self.conn.lock().unwrap();
+    conn.execute("DELETE FROM outbox WHERE id = ?1", params![id])?;
+    Ok(())
+  }
+
+  /// Records a failed attempt, scheduling the next one with exponential backoff or moving
+  /// the delivery to the dead-letter table once `max_attempts` is reached.
+  pub fn record_failure(&self, id: i64, error: &str) -> rusqlite::Result<FailureOutcome> {
+    let now = util::unix_timestamp();
+    let mut conn = self.conn.lock().unwrap();
+    let tx = conn.transaction()?;
+
+    let attempts: u32 = tx.query_row(
+      "SELECT attempts FROM outbox WHERE id = ?1",
+      params![id],
+      |row| row.get(0),
+    )?;
+    let attempts = attempts + 1;
+
+    let outcome = if attempts >= self.max_attempts {
+      tx.execute(
+        "INSERT INTO dead_letters (receipt_id, transaction_id, org_id, address, event_id,
+          event_type, secret, envelope, attempts, last_error, created_at, failed_at)
+        SELECT receipt_id, transaction_id, org_id, address, event_id, event_type, secret,
+          envelope, ?2, ?3, created_at, ?4
+        FROM outbox WHERE id = ?1",
+        params![id, attempts, error, now],
+      )?;
+      tx.execute("DELETE FROM outbox WHERE id = ?1", params![id])?;
+      FailureOutcome::DeadLettered
+    } else {
+      let next_attempt_at = now + util::retry_delay(self.retry_base_secs, attempts);
+      tx.execute(
+        "UPDATE outbox SET status = 'pending', attempts = ?2, last_error = ?3,
+          next_attempt_at = ?4, updated_at = ?5
+        WHERE id = ?1",
+        params![id, attempts, error, next_attempt_at, now],
+      )?;
+      FailureOutcome::Retry { next_attempt_at }
+    };
+
+    tx.commit()?;
+    Ok(outcome)
+  }
+
+  /// Leases up to `limit` deliveries that are due: pending deliveries whose backoff has
+  /// elapsed, and in-flight deliveries whose attempt outlived its lease
+  pub fn claim_due(&self, now: i64, limit: usize) -> rusqlite::Result<Vec<Delivery>> {
+    let mut conn = self.conn.lock().unwrap();
+    let tx = conn.transaction()?;
+
+    let deliveries = {
+      let mut stmt = tx.prepare(
+        "SELECT id, receipt_id, transaction_id, org_id, address, event_id, event_type, secret,
+          envelope, attempts, created_at
+        FROM outbox WHERE next_attempt_at <= ?1
+        ORDER BY next_attempt_at LIMIT ?2",
+      )?;
+      let rows = stmt.query_map(params![now, limit as i64], |row| {
+        let envelope: String = row.get(8)?;
+        Ok(Delivery {
+          id: row.get(0)?,
+          receipt_id: row.get(1)?,
+          transaction_id: row.get(2)?,
+          org_id: row.get(3)?,
+          address: row.get(4)?,
+          event_id: row.get(5)?,
+          event_type: row.get(6)?,
+          secret: row.get(7)?,
+          envelope: serde_json::from_str(&envelope).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(8, rusqlite::types::Type::Text, e.into())
+          })?,
+          attempts: row.get(9)?,
+          created_at: row.get(10)?,
+        })
+      })?;
+      rows.collect::<rusqlite::Result<Vec<_>>>()?
+    };
+
+    for delivery in &deliveries {
+      tx.execute(
+        "UPDATE outbox SET status = 'in_flight', next_attempt_at = ?2, updated_at = ?3
+        WHERE id = ?1",
+        params![delivery.id, now + self.lease_secs, now],
+      )?;
+    }
+
+    tx.commit()?;
+    Ok(deliveries)
+  }
+
+  /// Deletes dead letters that failed more than the retention ago, returning how many
+  pub fn purge_dead_letters(&self, now: i64) -> rusqlite::Result<usize> {
+    let conn = self.conn.lock().unwrap();
+    conn.execute(
+      "DELETE FROM dead_letters WHERE failed_at <= ?1",
+      params![now - self.dead_letter_retention_secs],
+    )
+  }
+
+  pub fn dead_letters(&self) -> rusqlite::Result<Vec<DeadLetter>> {
+    let conn = self.conn.lock().unwrap();
+    let mut stmt = conn.prepare(
+      "SELECT id, receipt_id, org_id, address, attempts, last_error, created_at, failed_at
+      FROM dead_letters ORDER BY failed_at DESC",
+    )?;
+    let rows = stmt.query_map([], |row| {
+      Ok(DeadLetter {
+        id: row.get(0)?,
+        receipt_id: row.get(1)?,
+        org_id: row.get(2)?,
+        address: row.get(3)?,
+        attempts: row.get(4)?,
+        last_error: row.get(5)?,
+        created_at: row.get(6)?,
+        failed_at: row.get(7)?,
+      })
+    })?;
+    rows.collect()
+  }
+
+  /// Moves a dead-lettered delivery back into the outbox with a fresh attempt budget,
+  /// due immediately. Returns false if no dead letter exists with the given id.
+  pub fn replay_dead_letter(&self, id: i64) -> rusqlite::Result<bool> {
+    let now = util::unix_timestamp();
+    let mut conn = self.conn.lock().unwrap();
+    let tx = conn.transaction()?;
+
+    let exists = tx
+      .query_row(
+        "SELECT id FROM dead_letters WHERE id = ?1",
+        params![id],
+        |row| row.get::<_, i64>(0),
+      )
+      .optional()?
+      .is_some();
+    if !exists {
+      return Ok(false);
+    }
+
+    tx.execute(
+      "INSERT INTO outbox (receipt_id, transaction_id, org_id, address, event_id, event_type,
+        secret, envelope, last_error, next_attempt_at, created_at, updated_at)
+      SELECT receipt_id, transaction_id, org_id, address, event_id, event_type, secret,
+        envelope, last_error, ?2, created_at, ?2
+      FROM dead_letters WHERE id = ?1",
+      params![id, now],
+    )?;
+    tx.execute("DELETE FROM dead_letters WHERE id = ?1", params![id])?;
+    tx.commit()?;
+    Ok(true)
+  }
+}
+
+/// Periodically retries outbox deliveries whose backoff or lease has elapsed, queueing a
+/// delivery callback for each retry, and purges expired dead letters
+pub async fn run_worker(outbox: Arc<Outbox>, callback_queue: Arc<CallbackQueue>) {
+  let poll_interval = Duration::from_secs(crate::s_config::get_outbox_poll_secs());
+  loop {
+    tokio::time::sleep(poll_interval).await;
+
+    match outbox.purge_dead_letters(util::unix_timestamp()) {
+      Ok(0) => {}
+      Ok(purged) => info!("Purged {} expired dead letters from outbox", purged),
+      Err(e) => info!("Failed to purge dead letters from outbox: {:?}", e),
+    }
+
+    let due = match outbox.claim_due(util::unix_timestamp(), WORKER_BATCH_SIZE) {
+      Ok(val) => val,
+      Err(e) => {
+        info!("Failed to read due deliveries from outbox: {:?}", e);
+        continue;
+      }
+    };
+
+    for delivery in due {
+      retry_delivery(&outbox, &callback_queue, delivery).await;
+    }
+  }
+}
+
+/// Posts the stored envelope to the receiver as a webhook event signed with its secret,
+/// the same way the `versa` client delivers a freshly encrypted one
+async fn post_envelope(delivery: &Delivery) -> Result<(), String> {
+  let (client_id, _) = util::get_client_id_and_client_secret();
+  let event_type: TransactionEvent =
+    serde_json::from_value(Value::String(delivery.event_type.clone()))
+      .map_err(|e| format!("Unknown event type {}: {}", delivery.event_type, e))?;
+  let event = WebhookEvent {
+    event: event_type.into(),
+    event_id: Some(delivery.event_id.clone()),
+    event_at: Some(delivery.created_at),
+    delivery_id: None,
+    delivery_at: Some(util::unix_timestamp()),
+    data: ReceiverPayload {
+      sender_client_id: client_id,
+      receipt_id: delivery.receipt_id.clone(),
+      envelope: versa::protocol::Envelope {
+        encrypted: delivery.envelope.encrypted.clone(),
+        nonce: delivery.envelope.nonce.clone(),
+      },
+    },
+  };
+  let body = bytes::Bytes::from(
+    serde_json::to_vec(&event).map_err(|e| format!("Failed to serialize event: {}", e))?,
+  );
+  let token = protocol::hmac_util::generate_token(body.clone(), delivery.secret.clone()).await;
+
+  let res = reqwest::Client::new()
+    .post(&delivery.address)
+    .header("Content-Type", "application/json")
+    .header("X-Request-Signature", token)
+    .body(body)
+    .send()
+    .await
+    .map_err(|e| format!("NetworkError({:?})", e))?;
+  if res.status().is_success() {
+    Ok(())
+  } else {
+    Err(format!(
+      "RemoteClientError({}, {:?})",
+      res.status(),
+      res.text().await.unwrap_or_default()
+    ))
+  }
+}
+
+async fn retry_delivery(outbox: &Outbox, callback_queue: &Arc<CallbackQueue>, delivery: Delivery) {
+  info!(
+    "Retrying delivery of receipt {} to receiver {} at {} (attempt {})",
+    delivery.receipt_id,
+    delivery.org_id,
+    delivery.address,
+    delivery.attempts + 1
+  );
+
+  let started_at = Instant::now();
+  let timeout = Duration::from_secs(crate::s_config::get_delivery_timeout_secs());
+  let result = match tokio::time::timeout(timeout, post_envelope(&delivery)).await {
+    Ok(outcome) => outcome.map_err(|e| (DeliveryStatus::Failed, e)),
+    Err(_) => Err((
+      DeliveryStatus::TimedOut,
+      format!("Timed out after {}s", timeout.as_secs()),
+    )),
+  };
+  let latency_ms = started_at.elapsed().as_millis() as u64;
+
+  let (status, error, recorded) = match result {
+    Ok(_) => {
+      info!("Successfully sent to receiver: {}", delivery.address);
+      let recorded = outbox.mark_delivered(delivery.id).map(|_| None);
+      (DeliveryStatus::Delivered, None, recorded)
+    }
+    Err((status, e)) => {
+      info!("Failed to send to receiver: {}", e);
+      let recorded = match outbox.record_failure(delivery.id, &e) {
+        Ok(FailureOutcome::DeadLettered) => {
+          info!(
+            "Delivery of receipt {} to receiver {} moved to dead letters",
+            delivery.receipt_id, delivery.org_id
+          );
+          Ok(Some(FailureOutcome::DeadLettered))
+        }
+        Ok(outcome) => Ok(Some(outcome)),
+        Err(e) => Err(e),
+      };
+      (status, Some(e), recorded)
+    }
+  };
+
+  let failure = recorded.unwrap_or_else(|e| {
+    info!("Failed to record delivery outcome in outbox: {:?}", e);
+    None
+  });
+
+  let result = ReceiverDeliveryResult {
+    org_id: delivery.org_id,
+    address: delivery.address,
+    status,
+    error,
+    latency_ms,
+  };
+  callbacks::notify(
+    callback_queue,
+    DeliveryCallback {
+      receipt_id: delivery.receipt_id,
+      transaction_id: Some(delivery.transaction_id),
+      receivers: vec![ReceiverOutcome::new(
+        result,
+        delivery.attempts + 1,
+        failure.as_ref(),
+      )],
+    },
+  );
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use pretty_assertions::assert_eq;
+  use versa::protocol::VersaMode;
+
+  fn summary() -> ReceiptRegistrationSummary {
+    ReceiptRegistrationSummary {
+      mode: VersaMode::Test,
+      receipt_id: "rct_123".into(),
+      transaction_id: "txn_123".into(),
+    }
+  }
+
+  fn receiver() -> ReceiverInstruction {
+    serde_json::from_value(serde_json::json!({
+      "endpoint_url": "https://receiver.example.com/webhook",
+      "event_id": "evt_123",
+      "event_type": "receipt",
+      "org_id": "org_456",
+      "secret": "secret",
+      "address": "https://receiver.example.com/webhook",
+      "client_id": "org_456"
+    }))
+    .unwrap()
+  }
+
+  fn enqueue(outbox: &Outbox, encryption_key: &EncryptionKey, receipt: &Value) -> i64 {
+    outbox
+      .enqueue(&NewDelivery {
+        receiver: &receiver(),
+        summary: &summary(),
+        encryption_key,
+        receipt,
+      })
+      .unwrap()
+  }
+
+  fn encryption_key() -> EncryptionKey {
+    EncryptionKey(protocol::encryption::generate_key())
+  }
+
+  #[test]
+  fn test_exhausted_delivery_should_be_dead_lettered_and_replayable() {
+    let outbox = Outbox::open(":memory:", 2, 30, 60, 86_400).unwrap();
+    let encryption_key = encryption_key();
+    let receipt = serde_json::json!({ "schema_version": "1.10.0" });
+    let id = enqueue(&outbox, &encryption_key, &receipt);
+
+    let now = util::unix_timestamp();
+    assert!(outbox.claim_due(now, 10).unwrap().is_empty());
+
+    let Ok(FailureOutcome::Retry { .. }) = outbox.record_failure(id, "timeout") else {
+      panic!("The first failure should be retried");
+    };
+    assert_eq!(
+      outbox.record_failure(id, "timeout").unwrap(),
+      FailureOutcome::DeadLettered
+    );
+
+    let dead_letters = outbox.dead_letters().unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].attempts, 2);
+    assert_eq!(dead_letters[0].last_error.as_deref(), Some("timeout"));
+
+    assert!(outbox.replay_dead_letter(dead_letters[0].id).unwrap());
+    assert!(outbox.dead_letters().unwrap().is_empty());
+
+    let due = outbox.claim_due(now, 10).unwrap();
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].attempts, 0);
+    assert_eq!(due[0].transaction_id, "txn_123");
+    assert_eq!(due[0].event_type, "receipt");
+    assert_eq!(
+      protocol::encryption::decrypt_envelope(&due[0].envelope, &encryption_key.0).unwrap(),
+      receipt
+    );
+  }
+
+  #[test]
+  fn test_outbox_should_not_store_plaintext_receipt_or_key() {
+    let outbox = Outbox::open(":memory:", 8, 30, 60, 86_400).unwrap();
+    let encryption_key = encryption_key();
+    let receipt = serde_json::json!({ "header": { "invoice_number": "INV-SECRET-0001" } });
+    enqueue(&outbox, &encryption_key, &receipt);
+
+    let row: String = outbox
+      .conn
+      .lock()
+      .unwrap()
+      .query_row(
+        "SELECT receipt_id || transaction_id || org_id || address || event_id || event_type
+          || envelope FROM outbox",
+        [],
+        |row| row.get(0),
+      )
+      .unwrap();
+    assert!(!row.contains("INV-SECRET-0001"));
+    assert!(!row.contains(&encryption_key.0));
+  }
+
+  #[test]
+  fn test_expired_dead_letters_should_be_purged() {
+    let outbox = Outbox::open(":memory:", 1, 30, 60, 3600).unwrap();
+    let id = enqueue(&outbox, &encryption_key(), &serde_json::json!({}));
+    assert_eq!(
+      outbox.record_failure(id, "timeout").unwrap(),
+      FailureOutcome::DeadLettered
+    );
+
+    let now = util::unix_timestamp();
+    assert_eq!(outbox.purge_dead_letters(now + 3599).unwrap(), 0);
+    assert_eq!(outbox.dead_letters().unwrap().len(), 1);
+    assert_eq!(outbox.purge_dead_letters(now + 3600).unwrap(), 1);
+    assert!(outbox.dead_letters().unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_claimed_delivery_should_not_be_claimed_again_until_lease_expires() {
+    let outbox = Outbox::open(":memory:", 8, 30, 60, 86_400).unwrap();
+    let id = enqueue(&outbox, &encryption_key(), &serde_json::json!({}));
+
+    // The caller holds the lease while it makes the first attempt
+    let now = util::unix_timestamp();
+    assert!(outbox.claim_due(now + 59, 10).unwrap().is_empty());
+
+    // An attempt that never reported back is picked up once its lease expires, once
+    let claimed = outbox.claim_due(now + 60, 10).unwrap();
+    assert_eq!(claimed.len(), 1);
+    assert_eq!(claimed[0].id, id);
+    assert!(outbox.claim_due(now + 60, 10).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_delivered_row_should_be_deleted() {
+    let outbox = Outbox::open(":memory:", 8, 30, 60, 86_400).unwrap();
+    let id = enqueue(&outbox, &encryption_key(), &serde_json::json!({}));
+
+    outbox.mark_delivered(id).unwrap();
+
+    let remaining: i64 = outbox
+      .conn
+      .lock()
+      .unwrap()
+      .query_row("SELECT COUNT(*) FROM outbox", [], |row| row.get(0))
+      .unwrap();
+    assert_eq!(remaining, 0);
+    assert!(outbox
+      .claim_due(util::unix_timestamp() + 3600, 10)
+      .unwrap()
+      .is_empty());
+  }
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use futures::stream::{self, StreamExt};
+use http::HeaderMap;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::info;
+
+use super::{IdempotencyCheck, RegistrationObserver, SendReceiptResponse, SendRequestPayload};
+use crate::SenderState;
+
+/// Result of one item of a batch, with the status and body that `/sender/send` would have
+/// responded with: a JSON body in `response`, or a plain text error in `error`
+#[derive(Serialize)]
+pub struct BatchItemResult {
+  pub index: usize,
+  pub transaction_id: Option<String>,
+  pub status: u16,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub response: Option<Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+  /// Whether `response` is the stored response to an earlier send with the same
+  /// `transaction_id`
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  pub replayed: bool,
+}
+
+impl BatchItemResult {
+  fn new(
+    index: usize,
+    transaction_id: Option<String>,
+    result: Result<(http::StatusCode, Value), (http::StatusCode, String)>,
+  ) -> Self {
+    let (status, response, error) = match result {
+      Ok((status, response)) => (status, Some(response), None),
+      Err((status, error)) => (status, None, Some(error)),
+    };
+    Self {
+      index,
+      transaction_id,
+      status: status.as_u16(),
+      response,
+      error,
+      replayed: false,
+    }
+  }
+
+  /// Takes the status and body of a response built for `/sender/send`
+  async fn from_response(index: usize, transaction_id: Option<String>, response: Response) -> Self {
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+      .await
+      .unwrap_or_default();
+    let result = match serde_json::from_slice(&body) {
+      Ok(body) => Ok((status, body)),
+      Err(_) => Err((status, String::from_utf8_lossy(&body).into_owned())),
+    };
+    Self::new(index, transaction_id, result)
+  }
+}
+
+#[derive(Serialize)]
+pub struct SendBatchResponse {
+  pub results: Vec<BatchItemResult>,
+}
+
+fn is_ndjson(headers: &HeaderMap) -> bool {
+  headers
+    .get(http::header::CONTENT_TYPE)
+    .and_then(|val| val.to_str().ok())
+    .map(|val| val.starts_with("application/x-ndjson") || val.starts_with("application/jsonl"))
+    .unwrap_or(false)
+}
+
+/// Parses the batch body as either a JSON array or newline-delimited JSON. A malformed
+/// NDJSON line fails only that item, while a malformed JSON array fails the whole batch.
+fn parse_items(
+  headers: &HeaderMap,
+  body: &[u8],
+) -> Result<Vec<Result<SendRequestPayload, String>>, String> {
+  if is_ndjson(headers) {
+    let body = std::str::from_utf8(body).map_err(|e| format!("Invalid UTF-8 body: {}", e))?;
+    return Ok(
+      body
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|e| format!("Failed to parse item: {}", e)))
+        .collect(),
+    );
+  }
+
+  let items: Vec<SendRequestPayload> =
+    serde_json::from_slice(body).map_err(|e| format!("Failed to parse body: {}", e))?;
+  Ok(items.into_iter().map(Ok).collect())
+}
+
+pub async fn send_batch(
+  State(state): State<SenderState>,
+  headers: HeaderMap,
+  body: bytes::Bytes,
+) -> Result<Response, (axum::http::StatusCode, String)> {
+  let items = parse_items(&headers, &body).map_err(|e| (http::StatusCode::BAD_REQUEST, e))?;
+
+  let max_batch_size = crate::s_config::get_max_batch_size();
+  if items.len() > max_batch_size {
+    return Err((
+      http::StatusCode::PAYLOAD_TOO_LARGE,
+      format!(
+        "Batch contains {} items, the maximum is {}",
+        items.len(),
+        max_batch_size
+      ),
+    ));
+  }
+
+  info!("Processing batch of {} receipts", items.len());
+
+  // One sending client per schema version in the batch, shared by all of its items
+  let (client_id, client_secret) = util::get_client_id_and_client_secret();
+  let mut clients = HashMap::new();
+  for payload in items.iter().flatten() {
+    clients
+      .entry(payload.schema_version.clone())
+      .or_insert_with(|| {
+        versa::client::VersaClient::new(client_id.clone(), client_secret.clone())
+          .with_client_string(&util::get_client_string())
+          .sending_client(payload.schema_version.clone())
+      });
+  }
+
+  let state = &state;
+  let clients = &clients;
+  let mut results: Vec<BatchItemResult> = stream::iter(items.into_iter().enumerate())
+    .map(|(index, item)| async move {
+      let mut payload = match item {
+        Ok(val) => val,
+        Err(e) => {
+          return BatchItemResult::new(index, None, Err((http::StatusCode::BAD_REQUEST, e)));
+        }
+      };
+      let transaction_id = payload.transaction_id.clone();
+
+      let receipt = match payload.take_receipt(&state.adapters) {
+        Ok(val) => val,
+        Err(e) => {
+          return BatchItemResult::new(
+            index,
+            transaction_id,
+            Err((http::StatusCode::BAD_REQUEST, e)),
+          );
+        }
+      };
+
+      if let Err(failure) = crate::dispatch::validate_receipt(&payload, &receipt).await {
+        let response = super::validation_failure_response(failure);
+        return BatchItemResult::from_response(index, transaction_id, response).await;
+      }
+
+      // Items are idempotent by transaction_id, like sends without an Idempotency-Key
+      let idempotency_key = transaction_id.clone();
+      if let Some(key) = &idempotency_key {
+        match super::begin_idempotent(state, key, &payload, &receipt) {
+          Ok(IdempotencyCheck::Proceed) => {}
+          Ok(IdempotencyCheck::Replay(status, response)) => {
+            let mut result = BatchItemResult::new(index, transaction_id, Ok((status, response)));
+            result.replayed = true;
+            return result;
+          }
+          Err(e) => return BatchItemResult::new(index, transaction_id, Err(e)),
+        }
+      }
+
+      let registration = RegistrationObserver::default();
+      let versa_client = &clients[&payload.schema_version];
+      let result =
+        crate::dispatch::send_receipt_with(state, versa_client, payload, receipt, &registration)
+          .await
+          .map(SendReceiptResponse::into_status_and_body);
+
+      if let Some(key) = &idempotency_key {
+        super::complete_idempotent(state, key, &result, registration.is_registered());
+      }
+
+      BatchItemResult::new(index, transaction_id, result)
+    })
+    .buffer_unordered(crate::s_config::get_batch_concurrency().max(1))
+    .collect()
+    .await;
+
+  results.sort_by_key(|result| result.index);
+
+  let any_failed = results
+    .iter()
+    .any(|result| result.status != http::StatusCode::OK.as_u16());
+  let status = if any_failed {
+    http::StatusCode::MULTI_STATUS
+  } else {
+    http::StatusCode::OK
+  };
+
+  Ok((status, axum::Json(SendBatchResponse { results })).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  fn ndjson_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+      http::header::CONTENT_TYPE,
+      "application/x-ndjson".parse().unwrap(),
+    );
+    headers
+  }
+
+  #[test]
+  fn test_malformed_ndjson_line_should_fail_only_that_item() {
+    let body = b"{\"handles\":{},\"transaction_id\":\"txn_1\"}\n\nnot json\n";
+    let items = parse_items(&ndjson_headers(), body).unwrap();
+    assert_eq!(items.len(), 2);
+    assert_eq!(
+      items[0].as_ref().unwrap().transaction_id.as_deref(),
+      Some("txn_1")
+    );
+    assert!(items[1].is_err());
+  }
+
+  #[test]
+  fn test_malformed_json_array_should_fail_the_batch() {
+    assert_eq!(
+      parse_items(&HeaderMap::new(), b"[{\"handles\":{}}]")
+        .unwrap()
+        .len(),
+      1
+    );
+    assert!(parse_items(&HeaderMap::new(), b"[{\"handles\":{}}, not json]").is_err());
+  }
+
+  #[tokio::test]
+  async fn test_batch_should_report_each_item_in_order() {
+    std::env::set_var("VERSA_CLIENT_ID", "versa_cid_test");
+    std::env::set_var("VERSA_CLIENT_SECRET", "versa_csk_test");
+
+    let sent =
+      "{\"handles\":{},\"transaction_id\":\"txn_4\",\"schema_version\":\"1.8.0\",\"receipt\":{}}";
+    let body = format!(
+      "{{\"handles\":{{}},\"transaction_id\":\"txn_1\"}}\n\
+       not json\n\
+       {{\"handles\":{{}},\"transaction_id\":\"txn_3\",\"schema_version\":\"0.1.0\",\"receipt\":{{}}}}\n\
+       {}\n",
+      sent
+    );
+
+    // txn_4 was already sent, so its stored response is replayed instead
+    let state = crate::test_state();
+    let mut payload: SendRequestPayload = serde_json::from_str(sent).unwrap();
+    let receipt = payload.take_receipt(&state.adapters).unwrap();
+    let Ok(IdempotencyCheck::Proceed) =
+      crate::routes::begin_idempotent(&state, "txn_4", &payload, &receipt)
+    else {
+      panic!("The key should be claimed");
+    };
+    let stored = serde_json::json!({ "receipt_id": "rct_4" });
+    crate::routes::complete_idempotent(
+      &state,
+      "txn_4",
+      &Ok((http::StatusCode::OK, stored.clone())),
+      true,
+    );
+
+    let response = send_batch(
+      State(state),
+      ndjson_headers(),
+      bytes::Bytes::from(body.into_bytes()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.status(), http::StatusCode::MULTI_STATUS);
+
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+      .await
+      .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let results: Vec<_> = body["results"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|result| {
+        (
+          result["index"].as_u64().unwrap(),
+          result["transaction_id"].as_str().map(String::from),
+          result["status"].as_u64().unwrap(),
+        )
+      })
+      .collect();
+    assert_eq!(
+      results,
+      vec![
+        (0, Some("txn_1".to_string()), 400),
+        (1, None, 400),
+        (2, Some("txn_3".to_string()), 422),
+        (3, Some("txn_4".to_string()), 200),
+      ]
+    );
+    assert_eq!(
+      body["results"][2]["response"]["misuse_code"],
+      "SchemaVersionInvalid"
+    );
+    assert_eq!(body["results"][3]["response"], stored);
+    assert_eq!(body["results"][3]["replayed"], true);
+  }
+}
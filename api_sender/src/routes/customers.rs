@@ -0,0 +1,79 @@
+use axum::extract::{Path, Query, State};
+use axum::response::{IntoResponse, Response};
+use http::HeaderMap;
+use protocol::customer_import::{self, ImportReport, ImportStatus};
+use protocol::customer_ledger::{self, CustomerEntry, CustomerLedger, CustomerQuery};
+use tracing::info;
+
+use crate::SenderState;
+
+/// Registers every customer in a CSV or JSONL import as a sender, requiring a
+/// `receiver_client_id` on each row, and records the registered rows in the ledger
+pub async fn import_customers(customers: &CustomerLedger, input: &str, csv: bool) -> ImportReport {
+  let (client_id, client_secret) = util::get_client_id_and_client_secret();
+  let schema_version = crate::s_config::get_default_schema_version();
+
+  let report = customer_import::register_all(
+    customer_import::parse_rows(input, csv),
+    crate::s_config::get_import_concurrency(),
+    |row| {
+      if row.receiver_client_id.is_none() {
+        return Err("receiver_client_id is required".to_string());
+      }
+      Ok(
+        versa::client::VersaClient::new(client_id.clone(), client_secret.clone())
+          .with_client_string(&util::get_client_string())
+          .sending_client(schema_version.clone()),
+      )
+    },
+  )
+  .await;
+
+  for result in &report.results {
+    if let (ImportStatus::Registered, Some(row)) = (&result.status, &result.row) {
+      if let Err(e) = customers.record_registered(
+        &row.handle,
+        &row.handle_type,
+        row.receiver_client_id.as_deref(),
+      ) {
+        info!("Failed to record customer in ledger: {:?}", e);
+      }
+    }
+  }
+
+  report
+}
+
+pub async fn import(
+  State(state): State<SenderState>,
+  headers: HeaderMap,
+  body: String,
+) -> Response {
+  let csv = customer_import::is_csv_content_type(
+    headers
+      .get(http::header::CONTENT_TYPE)
+      .and_then(|val| val.to_str().ok()),
+  );
+  let report = import_customers(&state.customers, &body, csv).await;
+  let status = if report.failed > 0 {
+    http::StatusCode::MULTI_STATUS
+  } else {
+    http::StatusCode::OK
+  };
+  (status, axum::Json(report)).into_response()
+}
+
+pub async fn list(
+  State(state): State<SenderState>,
+  Query(query): Query<CustomerQuery>,
+) -> Result<axum::Json<Vec<CustomerEntry>>, (axum::http::StatusCode, String)> {
+  customer_ledger::list_customers(&state.customers, &query).map(axum::Json)
+}
+
+/// Every ledger entry for the handle, including deregistrations
+pub async fn lookup(
+  State(state): State<SenderState>,
+  Path(handle): Path<String>,
+) -> Result<axum::Json<Vec<CustomerEntry>>, (axum::http::StatusCode, String)> {
+  customer_ledger::lookup_customer(&state.customers, &handle).map(axum::Json)
+}
@@ -0,0 +1,17 @@
+use axum::extract::{Path, State};
+
+use crate::jobs::Job;
+use crate::SenderState;
+
+pub async fn get_job(
+  State(state): State<SenderState>,
+  Path(id): Path<String>,
+) -> Result<axum::Json<Job>, (axum::http::StatusCode, String)> {
+  match state.jobs.get(&id) {
+    Some(job) => Ok(axum::Json(job)),
+    None => Err((
+      http::StatusCode::NOT_FOUND,
+      format!("No job found with id {}", id),
+    )),
+  }
+}
@@ -1,94 +1,402 @@
-use axum::extract::Json;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use axum::extract::{Json, Query, State};
+use axum::response::{IntoResponse, Response};
+use http::HeaderMap;
+use protocol::{model::Envelope, schema::ValidationFailure, CheckRegistryResponse, ReceiverInfo};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use versa::{
-  client_sender::VersaSender,
-  protocol::{customer_registration::HandleType, TransactionHandles},
+use versa::protocol::{
+  customer_registration::HandleType, webhook::TransactionEvent, Org, TransactionHandles, VersaMode,
 };
 
 use tracing::info;
 
+use crate::adapters::ReceiptAdapters;
+use crate::dispatch::SendProgress;
+use crate::idempotency::IdempotencyState;
+use crate::SenderState;
+
+pub mod batch;
+pub mod customers;
+pub mod jobs;
+pub mod outbox;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SendEvent {
+  #[default]
+  Receipt,
+  Itinerary,
+}
+
+impl SendEvent {
+  pub fn transaction_event(&self) -> TransactionEvent {
+    match self {
+      SendEvent::Receipt => TransactionEvent::Receipt,
+      SendEvent::Itinerary => TransactionEvent::Itinerary,
+    }
+  }
+
+  pub fn missing_data_message(&self) -> &'static str {
+    match self {
+      SendEvent::Receipt => "A receipt must be provided to the sending target",
+      SendEvent::Itinerary => "An itinerary must be provided to the sending target",
+    }
+  }
+}
+
 #[derive(Deserialize)]
+#[serde(from = "SendRequestBody")]
 pub struct SendRequestPayload {
+  pub event: SendEvent,
+  /// The receipt or itinerary data, matching `event`
   pub receipt: Option<Value>,
   pub schema_version: String,
   pub handles: TransactionHandles,
   pub transaction_id: Option<String>,
+  /// Format of `source`, converted into the receipt by the matching receipt adapter
+  pub format: Option<String>,
+  pub source: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct SendRequestBody {
+  #[serde(default)]
+  event: SendEvent,
+  #[serde(alias = "itinerary")]
+  receipt: Option<Value>,
+  /// Defaults to the configured `VERSA_SCHEMA_VERSION`, or for a `source` to convert to
+  /// the version the receipt adapters emit, see `adapters::default_schema_version`
+  #[serde(default)]
+  schema_version: Option<String>,
+  handles: TransactionHandles,
+  transaction_id: Option<String>,
+  #[serde(default)]
+  format: Option<String>,
+  #[serde(default)]
+  source: Option<Value>,
+}
+
+impl From<SendRequestBody> for SendRequestPayload {
+  fn from(body: SendRequestBody) -> Self {
+    let schema_version = match (body.schema_version, &body.format) {
+      (Some(schema_version), _) => schema_version,
+      (None, Some(_)) => crate::adapters::default_schema_version(),
+      (None, None) => crate::s_config::get_default_schema_version(),
+    };
+    Self {
+      event: body.event,
+      receipt: body.receipt,
+      schema_version,
+      handles: body.handles,
+      transaction_id: body.transaction_id,
+      format: body.format,
+      source: body.source,
+    }
+  }
+}
+
+impl SendRequestPayload {
+  /// Takes the receipt or itinerary out of the payload, converting `source` with the
+  /// adapter for `format` when one is given instead
+  pub fn take_receipt(&mut self, adapters: &ReceiptAdapters) -> Result<Value, String> {
+    match (self.receipt.take(), self.format.take(), self.source.take()) {
+      (Some(receipt), None, None) => Ok(receipt),
+      (None, Some(format), Some(source)) => {
+        adapters.convert(&format, &source, &self.schema_version)
+      }
+      (None, None, None) => Err(self.event.missing_data_message().to_string()),
+      _ => Err("Provide either a receipt, or a format and source to convert".to_string()),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+  Delivered,
+  Failed,
+  TimedOut,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ReceiverDeliveryResult {
+  pub org_id: String,
+  pub address: String,
+  pub status: DeliveryStatus,
+  pub error: Option<String>,
+  pub latency_ms: u64,
 }
 
 #[derive(Serialize)]
 pub struct SendReceiptResponse {
   pub receipt_id: String,
   pub transaction_id: String,
+  pub receivers: Vec<ReceiverDeliveryResult>,
 }
 
-pub async fn send(
-  Json(payload): Json<SendRequestPayload>,
-) -> Result<axum::Json<SendReceiptResponse>, (axum::http::StatusCode, String)> {
-  let (client_id, client_secret) = util::get_client_id_and_client_secret();
-
-  let Some(receipt) = payload.receipt else {
-    return Err((
-      http::StatusCode::BAD_REQUEST,
-      "A receipt must be provided to the sending target".to_string(),
-    ));
-  };
+impl SendReceiptResponse {
+  pub fn into_status_and_body(self) -> (http::StatusCode, Value) {
+    (self.status_code(), serde_json::to_value(self).unwrap())
+  }
 
-  let versa_client = versa::client::VersaClient::new(client_id, client_secret)
-    .with_client_string(&util::get_client_string())
-    .sending_client(payload.schema_version);
+  /// 207 Multi-Status when only some receivers failed, and 502 Bad Gateway when none were
+  /// delivered to, so callers can tell partial and failed delivery apart from success
+  /// without inspecting each result
+  pub fn status_code(&self) -> http::StatusCode {
+    let delivered = self
+      .receivers
+      .iter()
+      .filter(|r| r.status == DeliveryStatus::Delivered)
+      .count();
+    if delivered == self.receivers.len() {
+      http::StatusCode::OK
+    } else if delivered == 0 {
+      http::StatusCode::BAD_GATEWAY
+    } else {
+      http::StatusCode::MULTI_STATUS
+    }
+  }
+}
 
-  // 1. Register with Versa registry
+#[derive(Debug, Serialize)]
+pub struct InvalidReceiptResponse {
+  pub message: String,
+  pub misuse_code: String,
+  pub errors: Vec<String>,
+}
 
-  let registration_response = versa_client
-    .register_receipt(payload.handles, payload.transaction_id)
-    .await
-    .map_err(|e| {
-      info!("Registration failed: {:?}", e);
+/// 422 with every schema violation for invalid receipts, or 503 when the schema itself
+/// could not be loaded
+pub fn validation_failure_response(failure: ValidationFailure) -> Response {
+  match failure {
+    ValidationFailure::Invalid { code, errors } => {
+      info!("Payload failed schema validation: {:?}", errors);
+      let body = InvalidReceiptResponse {
+        message: "Payload failed schema validation".to_string(),
+        misuse_code: format!("{:?}", code),
+        errors,
+      };
+      (http::StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response()
+    }
+    ValidationFailure::SchemaUnavailable(e) => {
+      info!("Schema validation unavailable: {}", e);
       (
         http::StatusCode::SERVICE_UNAVAILABLE,
-        format!("Registration failed: {:?}", e),
+        format!("Schema validation unavailable: {}", e),
       )
-    })?;
+        .into_response()
+    }
+  }
+}
 
-  info!(
-    "Registration successful, received {} receivers",
-    registration_response.receivers.len()
-  );
+#[derive(Default, Deserialize)]
+pub struct SendOptions {
+  #[serde(default, rename = "async")]
+  pub asynchronous: bool,
+  #[serde(default)]
+  pub dry_run: bool,
+}
 
-  let (encryption_key, summary, receivers) = registration_response.ready_for_delivery();
+#[derive(Serialize)]
+pub struct SendJobAccepted {
+  pub job_id: String,
+}
 
-  // 2 and 3. Encrypt and send to each receiver
+#[derive(Serialize)]
+pub struct DryRunSummary {
+  pub event: SendEvent,
+  pub schema_version: String,
+  pub transaction_id: Option<String>,
+  /// SHA-1 digest of the canonical JSON that would be encrypted
+  pub digest: String,
+}
 
-  for receiver in receivers {
-    info!(
-      "Encrypting and sending envelope to receiver {} at {}",
-      receiver.org_id, receiver.address
-    );
-    let endpoint_url = receiver.address.clone();
-    match versa_client
-      .encrypt_and_send(receiver, summary.clone(), encryption_key.clone(), &receipt)
-      .await
+#[derive(Serialize)]
+pub struct DryRunDelivery {
+  pub client_id: String,
+  pub receiver: Option<Org>,
+  /// Scheme, host and port of the receiver's webhook address, without any credentials,
+  /// path or query that could carry a secret
+  pub address: Option<String>,
+  pub envelope: Envelope,
+}
+
+#[derive(Serialize)]
+pub struct DryRunSendResponse {
+  pub mode: VersaMode,
+  pub summary: DryRunSummary,
+  /// Throwaway key the envelopes were encrypted with, so they can be inspected
+  pub encryption_key: String,
+  pub deliveries: Vec<DryRunDelivery>,
+}
+
+/// Digest of everything that determines what a send registers and delivers, so that an
+/// idempotency key cannot be reused for a different request
+fn request_fingerprint(payload: &SendRequestPayload, receipt: &Value) -> Result<String, String> {
+  protocol::encryption::canonical_digest(&serde_json::json!({
+    "event": payload.event,
+    "schema_version": payload.schema_version,
+    "handles": payload.handles,
+    "transaction_id": payload.transaction_id,
+    "receipt": receipt,
+  }))
+}
+
+/// Notes whether a send got as far as registering its receipt
+#[derive(Default)]
+pub(crate) struct RegistrationObserver {
+  registered: AtomicBool,
+}
+
+impl RegistrationObserver {
+  pub(crate) fn is_registered(&self) -> bool {
+    self.registered.load(Ordering::SeqCst)
+  }
+}
+
+impl SendProgress for RegistrationObserver {
+  fn registered(&self, _receipt_id: &str, _transaction_id: &str, _receivers: usize) {
+    self.registered.store(true, Ordering::SeqCst);
+  }
+}
+
+pub(crate) enum IdempotencyCheck {
+  /// The key is claimed by this request, which should go ahead and send
+  Proceed,
+  /// The key already completed, with this status and response
+  Replay(http::StatusCode, Value),
+}
+
+/// Claims an idempotency key for a send, failing when the key is in progress or was used
+/// for a different request
+pub(crate) fn begin_idempotent(
+  state: &SenderState,
+  key: &str,
+  payload: &SendRequestPayload,
+  receipt: &Value,
+) -> Result<IdempotencyCheck, (axum::http::StatusCode, String)> {
+  let fingerprint = request_fingerprint(payload, receipt).map_err(|e| {
+    info!("Failed to fingerprint request: {}", e);
+    (
+      http::StatusCode::INTERNAL_SERVER_ERROR,
+      "Failed to check idempotency key".to_string(),
+    )
+  })?;
+  match state.idempotency.begin(key, &fingerprint) {
+    Ok(IdempotencyState::Started) => Ok(IdempotencyCheck::Proceed),
+    Ok(IdempotencyState::InProgress) => Err((
+      http::StatusCode::CONFLICT,
+      format!("A request with idempotency key {} is in progress", key),
+    )),
+    Ok(IdempotencyState::Completed { status, response }) => {
+      info!("Replaying stored response for idempotency key {}", key);
+      let status = http::StatusCode::from_u16(status).unwrap_or(http::StatusCode::OK);
+      Ok(IdempotencyCheck::Replay(status, response))
+    }
+    Ok(IdempotencyState::Mismatch) => Err((
+      http::StatusCode::UNPROCESSABLE_ENTITY,
+      format!(
+        "Idempotency key {} was already used for a different request",
+        key
+      ),
+    )),
+    Err(e) => {
+      info!("Failed to check idempotency key {}: {:?}", key, e);
+      Err((
+        http::StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to check idempotency key".to_string(),
+      ))
+    }
+  }
+}
+
+/// Records the outcome of a send under its idempotency key. Once the receipt is registered,
+/// even a failed request keeps its key, so that a retry cannot register the receipt a
+/// second time.
+pub(crate) fn complete_idempotent(
+  state: &SenderState,
+  key: &str,
+  result: &Result<(http::StatusCode, Value), (http::StatusCode, String)>,
+  registered: bool,
+) {
+  let recorded = match result {
+    Ok((status, body)) => state.idempotency.complete(key, status.as_u16(), body),
+    Err((status, message)) if registered => {
+      state
+        .idempotency
+        .complete(key, status.as_u16(), &Value::String(message.clone()))
+    }
+    Err(_) => state.idempotency.release(key),
+  };
+  if let Err(e) = recorded {
+    info!("Failed to record idempotency key {}: {:?}", key, e);
+  }
+}
+
+pub async fn send(
+  State(state): State<SenderState>,
+  Query(options): Query<SendOptions>,
+  headers: HeaderMap,
+  Json(mut payload): Json<SendRequestPayload>,
+) -> Result<Response, (axum::http::StatusCode, String)> {
+  let receipt = payload
+    .take_receipt(&state.adapters)
+    .map_err(|e| (http::StatusCode::BAD_REQUEST, e))?;
+
+  if let Err(failure) = crate::dispatch::validate_receipt(&payload, &receipt).await {
+    return Ok(validation_failure_response(failure));
+  }
+
+  if options.dry_run {
+    let response = crate::dispatch::dry_run(payload, receipt).await?;
+    return Ok(Json(response).into_response());
+  }
+
+  let idempotency_key = headers
+    .get("Idempotency-Key")
+    .and_then(|val| val.to_str().ok())
+    .map(|val| val.to_string())
+    .or_else(|| payload.transaction_id.clone());
+
+  if let Some(key) = &idempotency_key {
+    if let IdempotencyCheck::Replay(status, response) =
+      begin_idempotent(&state, key, &payload, &receipt)?
     {
-      Ok(_) => info!("Successfully sent to receiver: {}", endpoint_url),
-      Err(e) => {
-        info!("Failed to send to receiver: {:?}", e)
-      }
+      return Ok((status, [("Idempotent-Replayed", "true")], Json(response)).into_response());
     }
   }
 
-  let receipt_id = summary.receipt_id;
-  let transaction_id = summary.transaction_id;
+  let registration = RegistrationObserver::default();
+  let result = if options.asynchronous {
+    let job_id = state.jobs.submit(state.clone(), payload, receipt);
+    info!("Accepted asynchronous send as job {}", job_id);
+    let accepted = SendJobAccepted { job_id };
+    Ok((
+      http::StatusCode::ACCEPTED,
+      serde_json::to_value(accepted).unwrap(),
+    ))
+  } else {
+    crate::dispatch::send_receipt(&state, payload, receipt, &registration)
+      .await
+      .map(SendReceiptResponse::into_status_and_body)
+  };
+
+  if let Some(key) = &idempotency_key {
+    complete_idempotent(&state, key, &result, registration.is_registered());
+  }
 
-  Ok(axum::Json(SendReceiptResponse {
-    receipt_id,
-    transaction_id,
-  }))
+  let (status, body) = result?;
+  Ok((status, Json(body)).into_response())
 }
 
 #[derive(Serialize)]
 pub struct DryRunResponse {
   pub has_receivers: bool,
+  pub mode: VersaMode,
+  /// Each receiver that would be sent the receipt, with its client_id and organization
+  pub receivers: Vec<ReceiverInfo>,
 }
 
 pub async fn check_registry(
@@ -111,9 +419,14 @@ pub async fn check_registry(
     registration_response.receivers.len()
   );
 
-  let has_receivers = registration_response.receivers.len() > 0;
+  let CheckRegistryResponse { mode, receivers } = registration_response;
+  let has_receivers = !receivers.is_empty();
 
-  Ok(axum::Json(DryRunResponse { has_receivers }))
+  Ok(axum::Json(DryRunResponse {
+    has_receivers,
+    mode,
+    receivers,
+  }))
 }
 
 #[derive(Deserialize)]
@@ -121,56 +434,191 @@ pub struct SenderCustomerReference {
   pub handle: String,
   pub handle_type: HandleType,
   pub receiver_client_id: String,
+  /// Overrides the configured default schema version for this request
+  #[serde(default)]
+  pub schema_version: Option<String>,
 }
 
-pub async fn register_customer(Json(payload): Json<SenderCustomerReference>) -> http::StatusCode {
+/// Resolves the schema version for a request, falling back to the configured default
+fn resolve_schema_version(
+  schema_version: Option<String>,
+) -> Result<String, (axum::http::StatusCode, String)> {
+  let schema_version = schema_version.unwrap_or_else(crate::s_config::get_default_schema_version);
+  if !protocol::schema::is_supported_schema_version(&schema_version) {
+    return Err((
+      http::StatusCode::BAD_REQUEST,
+      crate::dispatch::unsupported_schema_version_message(&schema_version),
+    ));
+  }
+  Ok(schema_version)
+}
+
+pub async fn register_customer(
+  State(state): State<SenderState>,
+  Json(payload): Json<SenderCustomerReference>,
+) -> Result<http::StatusCode, (axum::http::StatusCode, String)> {
   let (client_id, client_secret) = util::get_client_id_and_client_secret();
 
   let SenderCustomerReference {
     handle,
     handle_type,
     receiver_client_id,
+    schema_version,
   } = payload;
+  let schema_version = resolve_schema_version(schema_version)?;
 
   let versa_client = versa::client::VersaClient::new(client_id, client_secret)
     .with_client_string(&util::get_client_string())
-    .sending_client("1.8.0".into());
+    .sending_client(schema_version);
 
+  let handle_type_name = protocol::customer_registration::handle_type_name(&handle_type);
   match protocol::customer_registration::register_customer(
     versa_client,
-    handle,
+    handle.clone(),
     handle_type,
-    Some(receiver_client_id),
+    Some(receiver_client_id.clone()),
   )
   .await
   {
-    Ok(_) => http::StatusCode::OK,
-    Err(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+    Ok(_) => {
+      if let Err(e) =
+        state
+          .customers
+          .record_registered(&handle, &handle_type_name, Some(&receiver_client_id))
+      {
+        info!("Failed to record customer in ledger: {:?}", e);
+      }
+      Ok(http::StatusCode::OK)
+    }
+    Err(e) => {
+      info!("Customer registration failed: {:?}", e);
+      Err((
+        http::StatusCode::SERVICE_UNAVAILABLE,
+        format!("Customer registration failed: {:?}", e),
+      ))
+    }
   }
 }
 
-pub async fn deregister_customer(Json(payload): Json<SenderCustomerReference>) -> http::StatusCode {
+pub async fn deregister_customer(
+  State(state): State<SenderState>,
+  Json(payload): Json<SenderCustomerReference>,
+) -> Result<http::StatusCode, (axum::http::StatusCode, String)> {
   let (client_id, client_secret) = util::get_client_id_and_client_secret();
 
   let SenderCustomerReference {
     handle,
     handle_type,
     receiver_client_id,
+    schema_version,
   } = payload;
+  let schema_version = resolve_schema_version(schema_version)?;
 
   let versa_client = versa::client::VersaClient::new(client_id, client_secret)
     .with_client_string(&util::get_client_string())
-    .sending_client("1.8.0".into());
+    .sending_client(schema_version);
 
+  let handle_type_name = protocol::customer_registration::handle_type_name(&handle_type);
   match protocol::customer_registration::deregister_customer(
     versa_client,
-    handle,
+    handle.clone(),
     handle_type,
-    Some(receiver_client_id),
+    Some(receiver_client_id.clone()),
   )
   .await
   {
-    Ok(_) => http::StatusCode::OK,
-    Err(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+    Ok(_) => {
+      if let Err(e) =
+        state
+          .customers
+          .record_deregistered(&handle, &handle_type_name, Some(&receiver_client_id))
+      {
+        info!("Failed to record customer in ledger: {:?}", e);
+      }
+      Ok(http::StatusCode::OK)
+    }
+    Err(e) => {
+      info!("Customer deregistration failed: {:?}", e);
+      Err((
+        http::StatusCode::SERVICE_UNAVAILABLE,
+        format!("Customer deregistration failed: {:?}", e),
+      ))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  fn response(statuses: &[DeliveryStatus]) -> SendReceiptResponse {
+    SendReceiptResponse {
+      receipt_id: "rct_123".into(),
+      transaction_id: "txn_123".into(),
+      receivers: statuses
+        .iter()
+        .map(|status| ReceiverDeliveryResult {
+          org_id: "org_456".into(),
+          address: "https://receiver.example.com/webhook".into(),
+          status: *status,
+          error: None,
+          latency_ms: 0,
+        })
+        .collect(),
+    }
+  }
+
+  #[test]
+  fn test_status_code_should_reflect_how_many_receivers_were_delivered_to() {
+    use DeliveryStatus::*;
+
+    assert_eq!(response(&[]).status_code(), http::StatusCode::OK);
+    assert_eq!(
+      response(&[Delivered, Delivered]).status_code(),
+      http::StatusCode::OK
+    );
+    assert_eq!(
+      response(&[Delivered, TimedOut]).status_code(),
+      http::StatusCode::MULTI_STATUS
+    );
+    assert_eq!(
+      response(&[Failed, TimedOut]).status_code(),
+      http::StatusCode::BAD_GATEWAY
+    );
+  }
+
+  #[tokio::test]
+  async fn test_source_without_schema_version_should_convert_at_a_version_the_adapter_emits() {
+    let mut payload: SendRequestPayload = serde_json::from_value(serde_json::json!({
+      "format": "stripe_invoice",
+      "source": {
+        "object": "invoice",
+        "currency": "usd",
+        "created": 1718000000,
+        "lines": { "data": [{ "description": "Widget", "amount": 2000, "quantity": 2 }] }
+      },
+      "handles": { "customer_email": "jane@example.com" },
+    }))
+    .unwrap();
+    assert_eq!(payload.schema_version, "2.0.0");
+
+    let receipt = payload.take_receipt(&ReceiptAdapters::default()).unwrap();
+    assert_eq!(receipt["header"]["total"], 2000);
+    assert!(crate::dispatch::validate_receipt(&payload, &receipt)
+      .await
+      .is_ok());
+
+    // Receipts sent as-is still default to the configured version
+    let payload: SendRequestPayload = serde_json::from_value(serde_json::json!({
+      "receipt": receipt,
+      "handles": { "customer_email": "jane@example.com" },
+    }))
+    .unwrap();
+    assert_eq!(
+      payload.schema_version,
+      crate::s_config::get_default_schema_version()
+    );
   }
 }
@@ -0,0 +1,41 @@
+use axum::extract::{Path, State};
+use tracing::info;
+
+use crate::outbox::DeadLetter;
+use crate::SenderState;
+
+pub async fn dead_letters(
+  State(state): State<SenderState>,
+) -> Result<axum::Json<Vec<DeadLetter>>, (axum::http::StatusCode, String)> {
+  let dead_letters = state.outbox.dead_letters().map_err(|e| {
+    info!("Failed to read dead letters: {:?}", e);
+    (
+      http::StatusCode::INTERNAL_SERVER_ERROR,
+      "Failed to read dead letters".to_string(),
+    )
+  })?;
+  Ok(axum::Json(dead_letters))
+}
+
+pub async fn replay_dead_letter(
+  State(state): State<SenderState>,
+  Path(id): Path<i64>,
+) -> Result<axum::http::StatusCode, (axum::http::StatusCode, String)> {
+  match state.outbox.replay_dead_letter(id) {
+    Ok(true) => {
+      info!("Dead letter {} queued for redelivery", id);
+      Ok(http::StatusCode::ACCEPTED)
+    }
+    Ok(false) => Err((
+      http::StatusCode::NOT_FOUND,
+      format!("No dead letter found with id {}", id),
+    )),
+    Err(e) => {
+      info!("Failed to replay dead letter {}: {:?}", id, e);
+      Err((
+        http::StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to replay dead letter".to_string(),
+      ))
+    }
+  }
+}
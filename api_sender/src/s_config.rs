@@ -0,0 +1,133 @@
+pub fn get_outbox_path() -> String {
+  std::env::var("VERSA_OUTBOX_PATH").unwrap_or("versa_outbox.db".into())
+}
+
+pub fn get_outbox_max_attempts() -> u32 {
+  util::get_env_or("VERSA_OUTBOX_MAX_ATTEMPTS", 8)
+}
+
+pub fn get_outbox_retry_base_secs() -> i64 {
+  util::get_env_or("VERSA_OUTBOX_RETRY_BASE_SECS", 30)
+}
+
+pub fn get_outbox_poll_secs() -> u64 {
+  util::get_env_or("VERSA_OUTBOX_POLL_SECS", 15)
+}
+
+/// How long an attempt holds its delivery before the worker may retry it, which must
+/// exceed the delivery timeout
+pub fn get_outbox_lease_secs() -> i64 {
+  util::get_env_or("VERSA_OUTBOX_LEASE_SECS", 60)
+}
+
+/// How long dead-lettered deliveries are kept for replay before they are purged
+pub fn get_dead_letter_retention_secs() -> i64 {
+  util::get_env_or("VERSA_DEAD_LETTER_RETENTION_SECS", 7 * 86_400)
+}
+
+pub fn get_max_concurrent_jobs() -> usize {
+  util::get_env_or("VERSA_MAX_CONCURRENT_JOBS", 4)
+}
+
+pub fn get_job_retention_secs() -> i64 {
+  util::get_env_or("VERSA_JOB_RETENTION_SECS", 3600)
+}
+
+pub fn get_max_batch_size() -> usize {
+  util::get_env_or("VERSA_MAX_BATCH_SIZE", 10_000)
+}
+
+pub fn get_max_batch_bytes() -> usize {
+  util::get_env_or("VERSA_MAX_BATCH_BYTES", 64 * 1024 * 1024)
+}
+
+pub fn get_batch_concurrency() -> usize {
+  util::get_env_or("VERSA_BATCH_CONCURRENCY", 8)
+}
+
+pub fn get_idempotency_path() -> String {
+  std::env::var("VERSA_IDEMPOTENCY_PATH").unwrap_or("versa_idempotency.db".into())
+}
+
+pub fn get_idempotency_window_secs() -> i64 {
+  util::get_env_or("VERSA_IDEMPOTENCY_WINDOW_SECS", 86_400)
+}
+
+/// How long a request holds its idempotency key before a retry may take it over
+pub fn get_idempotency_lease_secs() -> i64 {
+  util::get_env_or("VERSA_IDEMPOTENCY_LEASE_SECS", 120)
+}
+
+pub fn get_schema_validation_enabled() -> bool {
+  util::get_env_or("VERSA_SENDER_SCHEMA_VALIDATION", true)
+}
+
+pub fn get_delivery_concurrency() -> usize {
+  util::get_env_or("VERSA_DELIVERY_CONCURRENCY", 8)
+}
+
+pub fn get_delivery_timeout_secs() -> u64 {
+  util::get_env_or("VERSA_DELIVERY_TIMEOUT_SECS", 10)
+}
+
+/// Schema version used when a request does not specify one
+pub fn get_default_schema_version() -> String {
+  std::env::var("VERSA_SCHEMA_VERSION").unwrap_or("1.8.0".into())
+}
+
+pub fn get_import_concurrency() -> usize {
+  util::get_env_or("VERSA_IMPORT_CONCURRENCY", 8)
+}
+
+pub fn get_max_import_bytes() -> usize {
+  util::get_env_or("VERSA_MAX_IMPORT_BYTES", 64 * 1024 * 1024)
+}
+
+pub fn get_customer_ledger_path() -> String {
+  std::env::var("VERSA_SENDER_CUSTOMERS_PATH").unwrap_or("versa_sender_customers.db".into())
+}
+
+pub fn get_delivery_callback_url() -> Option<String> {
+  std::env::var("VERSA_DELIVERY_CALLBACK_URL").ok()
+}
+
+pub fn get_delivery_callback_secret() -> Option<String> {
+  std::env::var("VERSA_DELIVERY_CALLBACK_SECRET").ok()
+}
+
+/// Signs callbacks over `{timestamp}.{body}` and sends the timestamp, instead of signing
+/// the body alone
+pub fn get_delivery_callback_timestamped() -> bool {
+  util::get_env_or("VERSA_DELIVERY_CALLBACK_TIMESTAMPED", false)
+}
+
+pub fn get_delivery_callback_path() -> String {
+  std::env::var("VERSA_DELIVERY_CALLBACK_PATH").unwrap_or("versa_delivery_callbacks.db".into())
+}
+
+pub fn get_delivery_callback_max_attempts() -> u32 {
+  util::get_env_or("VERSA_DELIVERY_CALLBACK_MAX_ATTEMPTS", 8)
+}
+
+pub fn get_delivery_callback_retry_base_secs() -> i64 {
+  util::get_env_or("VERSA_DELIVERY_CALLBACK_RETRY_BASE_SECS", 30)
+}
+
+pub fn get_delivery_callback_poll_secs() -> u64 {
+  util::get_env_or("VERSA_DELIVERY_CALLBACK_POLL_SECS", 15)
+}
+
+pub fn get_delivery_callback_timeout_secs() -> u64 {
+  util::get_env_or("VERSA_DELIVERY_CALLBACK_TIMEOUT_SECS", 10)
+}
+
+/// How long an attempt holds its callback before the worker may retry it, which must
+/// exceed the callback timeout
+pub fn get_delivery_callback_lease_secs() -> i64 {
+  util::get_env_or("VERSA_DELIVERY_CALLBACK_LEASE_SECS", 60)
+}
+
+/// Rejects receipts whose amounts do not add up
+pub fn get_consistency_checks_enabled() -> bool {
+  util::get_env_or("VERSA_SENDER_CONSISTENCY_CHECKS", false)
+}
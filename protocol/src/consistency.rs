@@ -0,0 +1,359 @@
+use serde::Serialize;
+use serde_json::Value;
+use versa::protocol::misuse::MisuseCode;
+
+/// Amounts computed from rates may be off by a rounding step
+const ROUNDING_TOLERANCE: i64 = 1;
+/// 2000-01-01T00:00:00Z, earlier timestamps are almost certainly wrong
+const MIN_TIMESTAMP: i64 = 946_684_800;
+/// Allowed clock skew for timestamps in the future
+const MAX_FUTURE_SECS: i64 = 86_400;
+
+/// ISO 4217 currency codes
+const CURRENCY_CODES: &[&str] = &[
+  "aed", "afn", "all", "amd", "ang", "aoa", "ars", "aud", "awg", "azn", "bam", "bbd", "bdt", "bgn",
+  "bhd", "bif", "bmd", "bnd", "bob", "brl", "bsd", "btn", "bwp", "byn", "bzd", "cad", "cdf", "chf",
+  "clp", "cny", "cop", "crc", "cup", "cve", "czk", "djf", "dkk", "dop", "dzd", "egp", "ern", "etb",
+  "eur", "fjd", "fkp", "gbp", "gel", "ghs", "gip", "gmd", "gnf", "gtq", "gyd", "hkd", "hnl", "htg",
+  "huf", "idr", "ils", "inr", "iqd", "irr", "isk", "jmd", "jod", "jpy", "kes", "kgs", "khr", "kmf",
+  "kpw", "krw", "kwd", "kyd", "kzt", "lak", "lbp", "lkr", "lrd", "lsl", "lyd", "mad", "mdl", "mga",
+  "mkd", "mmk", "mnt", "mop", "mru", "mur", "mvr", "mwk", "mxn", "myr", "mzn", "nad", "ngn", "nio",
+  "nok", "npr", "nzd", "omr", "pab", "pen", "pgk", "php", "pkr", "pln", "pyg", "qar", "ron", "rsd",
+  "rub", "rwf", "sar", "sbd", "scr", "sdg", "sek", "sgd", "shp", "sle", "sll", "sos", "srd", "ssp",
+  "stn", "svc", "syp", "szl", "thb", "tjs", "tmt", "tnd", "top", "try", "ttd", "twd", "tzs", "uah",
+  "ugx", "usd", "uyu", "uzs", "ves", "vnd", "vuv", "wst", "xaf", "xcd", "xof", "xpf", "yer", "zar",
+  "zmw", "zwl",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+  /// The receipt contradicts itself
+  Error,
+  /// The receipt is suspicious, e.g. amounts that differ by more than rounding
+  Warning,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Finding {
+  /// Stable identifier of the check, e.g. `header.total`
+  pub rule: &'static str,
+  /// JSON pointer to the offending value
+  pub path: String,
+  pub severity: Severity,
+  pub message: String,
+}
+
+impl std::fmt::Display for Finding {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}: {} ({})", self.path, self.message, self.rule)
+  }
+}
+
+pub fn has_errors(findings: &[Finding]) -> bool {
+  findings
+    .iter()
+    .any(|finding| finding.severity == Severity::Error)
+}
+
+/// The misuse code to report for the findings, if any of them is an error. Inconsistent
+/// receipts are schema valid, so they are reported as failing semantic validation.
+pub fn misuse_code(findings: &[Finding]) -> Option<MisuseCode> {
+  has_errors(findings).then_some(MisuseCode::SemanticValidationFailed)
+}
+
+struct Checker {
+  findings: Vec<Finding>,
+  now: i64,
+}
+
+impl Checker {
+  fn push(&mut self, rule: &'static str, path: String, severity: Severity, message: String) {
+    self.findings.push(Finding {
+      rule,
+      path,
+      severity,
+      message,
+    });
+  }
+
+  fn check_timestamp(&mut self, value: &Value, path: String) {
+    let Some(timestamp) = value.as_i64() else {
+      return;
+    };
+    if timestamp < MIN_TIMESTAMP {
+      self.push(
+        "timestamp",
+        path,
+        Severity::Warning,
+        format!("Timestamp {} is before the year 2000", timestamp),
+      );
+    } else if timestamp > self.now + MAX_FUTURE_SECS {
+      self.push(
+        "timestamp",
+        path,
+        Severity::Warning,
+        format!(
+          "Timestamp {} is in the future, timestamps must be in seconds",
+          timestamp
+        ),
+      );
+    }
+  }
+}
+
+fn amount(value: &Value, key: &str) -> i64 {
+  value.get(key).and_then(Value::as_i64).unwrap_or(0)
+}
+
+fn items<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+  value
+    .get(key)
+    .and_then(Value::as_array)
+    .map(Vec::as_slice)
+    .unwrap_or_default()
+}
+
+fn sum(values: &[Value], key: &str) -> i64 {
+  values.iter().map(|value| amount(value, key)).sum()
+}
+
+/// Checks that a receipt's amounts add up and its currency and timestamps are sane.
+/// Totals are only reconciled against general itemization line items, since other
+/// itemizations price their contents differently.
+pub fn check_receipt(receipt: &Value) -> Vec<Finding> {
+  let mut checker = Checker {
+    findings: Vec::new(),
+    now: util::unix_timestamp(),
+  };
+  let Some(header) = receipt.get("header") else {
+    return checker.findings;
+  };
+
+  if let Some(currency) = header.get("currency").and_then(Value::as_str) {
+    if !CURRENCY_CODES.contains(&currency.to_lowercase().as_str()) {
+      checker.push(
+        "header.currency",
+        "/header/currency".into(),
+        Severity::Error,
+        format!("{} is not an ISO 4217 currency code", currency),
+      );
+    }
+  }
+
+  checker.check_timestamp(
+    header.get("invoiced_at").unwrap_or(&Value::Null),
+    "/header/invoiced_at".into(),
+  );
+
+  let total = amount(header, "total");
+  let paid = amount(header, "paid");
+  if paid > total {
+    checker.push(
+      "header.paid",
+      "/header/paid".into(),
+      Severity::Error,
+      format!("paid {} exceeds total {}", paid, total),
+    );
+  }
+
+  let payments = items(receipt, "payments");
+  if !payments.is_empty() && sum(payments, "amount") != paid {
+    checker.push(
+      "payments.sum",
+      "/payments".into(),
+      Severity::Error,
+      format!(
+        "Payments sum to {}, but header.paid is {}",
+        sum(payments, "amount"),
+        paid
+      ),
+    );
+  }
+  for (index, payment) in payments.iter().enumerate() {
+    checker.check_timestamp(
+      payment.get("paid_at").unwrap_or(&Value::Null),
+      format!("/payments/{}/paid_at", index),
+    );
+  }
+
+  let Some(general) = receipt
+    .get("itemization")
+    .and_then(|itemization| itemization.get("general"))
+    .filter(|general| general.is_object())
+  else {
+    return checker.findings;
+  };
+
+  // Schema 1.11.0 renamed `line_items` to `items` and the line item `subtotal` to `amount`
+  let (items_key, subtotal_key) = match general.get("items") {
+    Some(_) => ("items", "amount"),
+    None => ("line_items", "subtotal"),
+  };
+  let line_items = items(general, items_key);
+  let mut line_totals = 0;
+  for (index, item) in line_items.iter().enumerate() {
+    let path = format!("/itemization/general/{}/{}", items_key, index);
+    let subtotal = amount(item, subtotal_key);
+
+    if let (Some(quantity), Some(unit_cost)) = (
+      item.get("quantity").and_then(Value::as_f64),
+      item.get("unit_cost").and_then(Value::as_i64),
+    ) {
+      let expected = (quantity * unit_cost as f64).round() as i64;
+      if (expected - subtotal).abs() > ROUNDING_TOLERANCE {
+        checker.push(
+          "line_item.subtotal",
+          format!("{}/{}", path, subtotal_key),
+          Severity::Warning,
+          format!(
+            "{} {} differs from quantity {} * unit_cost {}",
+            subtotal_key, subtotal, quantity, unit_cost
+          ),
+        );
+      }
+    }
+
+    let adjustments = sum(items(item, "adjustments"), "amount");
+    let taxes = items(item, "taxes");
+    for (tax_index, tax) in taxes.iter().enumerate() {
+      let Some(rate) = tax.get("rate").and_then(Value::as_f64) else {
+        continue;
+      };
+      let expected = ((subtotal + adjustments) as f64 * rate).round() as i64;
+      let actual = amount(tax, "amount");
+      if (expected - actual).abs() > ROUNDING_TOLERANCE {
+        checker.push(
+          "line_item.tax",
+          format!("{}/taxes/{}/amount", path, tax_index),
+          Severity::Warning,
+          format!(
+            "Tax amount {} differs from {} at rate {}",
+            actual, expected, rate
+          ),
+        );
+      }
+    }
+
+    line_totals += subtotal + adjustments + sum(taxes, "amount");
+  }
+
+  if line_items.is_empty() {
+    return checker.findings;
+  }
+
+  let subtotal = sum(line_items, subtotal_key);
+  if subtotal != amount(header, "subtotal") {
+    checker.push(
+      "header.subtotal",
+      "/header/subtotal".into(),
+      Severity::Error,
+      format!(
+        "Line item subtotals sum to {}, but header.subtotal is {}",
+        subtotal,
+        amount(header, "subtotal")
+      ),
+    );
+  }
+
+  let expected_total = line_totals + sum(items(general, "invoice_level_adjustments"), "amount");
+  if expected_total != total {
+    checker.push(
+      "header.total",
+      "/header/total".into(),
+      Severity::Error,
+      format!(
+        "Line items with taxes and adjustments sum to {}, but header.total is {}",
+        expected_total, total
+      ),
+    );
+  }
+
+  checker.findings
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  fn receipt() -> Value {
+    serde_json::json!({
+      "schema_version": "1.10.0",
+      "header": {
+        "currency": "usd",
+        "total": 2212,
+        "subtotal": 1780,
+        "paid": 2212,
+        "invoiced_at": 1713295619
+      },
+      "itemization": {
+        "general": {
+          "line_items": [{
+            "description": "Widget",
+            "subtotal": 1780,
+            "quantity": 10,
+            "unit_cost": 178,
+            "taxes": [{ "amount": 432, "rate": null, "name": "GST" }],
+            "adjustments": []
+          }],
+          "invoice_level_adjustments": []
+        }
+      },
+      "payments": [{ "amount": 2212, "paid_at": 1713295619, "payment_type": "card" }]
+    })
+  }
+
+  #[test]
+  fn test_consistent_receipt_should_have_no_findings() {
+    assert_eq!(check_receipt(&receipt()), Vec::new());
+  }
+
+  #[test]
+  fn test_inconsistent_receipt_should_report_findings() {
+    let mut data = receipt();
+    data["header"]["currency"] = "usx".into();
+    data["header"]["subtotal"] = 1800.into();
+    data["header"]["paid"] = 2300.into();
+    data["payments"][0]["paid_at"] = 1713295619000_i64.into();
+
+    let findings = check_receipt(&data);
+    let rules: Vec<&str> = findings.iter().map(|finding| finding.rule).collect();
+    assert_eq!(
+      rules,
+      vec![
+        "header.currency",
+        "header.paid",
+        "payments.sum",
+        "timestamp",
+        "header.subtotal"
+      ]
+    );
+    assert_eq!(
+      misuse_code(&findings),
+      Some(MisuseCode::SemanticValidationFailed)
+    );
+  }
+
+  #[test]
+  fn test_receipt_with_items_should_be_checked() {
+    let mut data = receipt();
+    let general = &mut data["itemization"]["general"];
+    let mut items = general["line_items"].take();
+    items[0]["amount"] = items[0]["subtotal"].take();
+    general["items"] = items;
+    general.as_object_mut().unwrap().remove("line_items");
+    assert_eq!(check_receipt(&data), Vec::new());
+
+    data["itemization"]["general"]["items"][0]["amount"] = 1700.into();
+    let rules: Vec<&str> = check_receipt(&data)
+      .iter()
+      .map(|finding| finding.rule)
+      .collect();
+    assert_eq!(
+      rules,
+      vec!["line_item.subtotal", "header.subtotal", "header.total"]
+    );
+  }
+}
@@ -0,0 +1,344 @@
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use versa::client::customer_registration::CustomerRegistration;
+
+use crate::customer_registration::parse_handle_type;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportStatus {
+  Registered,
+  Failed,
+}
+
+/// A single customer to register. Rows of a previous [`ImportReport`] parse as import rows
+/// too, and rows that were already registered are skipped, so a report can be re-run to
+/// retry only its failures.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImportRow {
+  pub handle: String,
+  /// The snake_case name of the handle type, checked when the row is registered
+  pub handle_type: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub receiver_client_id: Option<String>,
+  #[serde(default, skip_serializing)]
+  pub status: Option<ImportStatus>,
+}
+
+/// A line that could not be parsed as an import row
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnparsedRow {
+  /// The line as it appeared in the input
+  pub raw: String,
+  pub error: String,
+}
+
+#[derive(Serialize)]
+pub struct ImportRowResult {
+  /// 1-based line of the row in the input
+  pub line: usize,
+  #[serde(flatten)]
+  pub row: Option<ImportRow>,
+  /// The input line, for rows that could not be parsed
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub raw: Option<String>,
+  pub status: ImportStatus,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+  pub registered: usize,
+  pub failed: usize,
+  /// Rows already registered by a previous run of the same report
+  pub skipped: usize,
+  pub results: Vec<ImportRowResult>,
+}
+
+impl ImportReport {
+  /// The report as JSONL, one result per line, suitable for re-running failures
+  pub fn to_jsonl(&self) -> String {
+    self
+      .results
+      .iter()
+      .map(|result| serde_json::to_string(result).unwrap() + "\n")
+      .collect()
+  }
+}
+
+/// Imports are CSV when sent as `text/csv`, and JSONL otherwise
+pub fn is_csv_content_type(content_type: Option<&str>) -> bool {
+  content_type
+    .map(|val| val.starts_with("text/csv"))
+    .unwrap_or(false)
+}
+
+/// Parses a CSV (with a `handle,handle_type,receiver_client_id` header) or JSONL import.
+/// Each entry keeps its line number, and a malformed row fails only that row.
+pub fn parse_rows(input: &str, csv: bool) -> Vec<(usize, Result<ImportRow, UnparsedRow>)> {
+  let mut lines = input
+    .lines()
+    .enumerate()
+    .map(|(index, line)| (index + 1, line))
+    .filter(|(_, line)| !line.trim().is_empty());
+
+  if !csv {
+    return lines
+      .map(|(line_no, line)| {
+        let row = serde_json::from_str(line)
+          .map_err(|e| unparsed(line, format!("Failed to parse row: {}", e)));
+        (line_no, row)
+      })
+      .collect();
+  }
+
+  let header = match lines.next() {
+    Some((_, header)) => split_csv_line(header),
+    None => return Vec::new(),
+  };
+
+  lines
+    .map(|(line_no, line)| {
+      let fields = split_csv_line(line);
+      if fields.len() != header.len() {
+        return (
+          line_no,
+          Err(unparsed(
+            line,
+            format!("Expected {} fields, found {}", header.len(), fields.len()),
+          )),
+        );
+      }
+      let object: serde_json::Map<String, serde_json::Value> = header
+        .iter()
+        .zip(fields)
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| (key.trim().to_string(), serde_json::Value::String(value)))
+        .collect();
+      let row = serde_json::from_value(serde_json::Value::Object(object))
+        .map_err(|e| unparsed(line, format!("Failed to parse row: {}", e)));
+      (line_no, row)
+    })
+    .collect()
+}
+
+fn unparsed(line: &str, error: String) -> UnparsedRow {
+  UnparsedRow {
+    raw: line.to_string(),
+    error,
+  }
+}
+
+/// Splits a CSV line into fields, honouring double-quoted fields and `""` escapes
+fn split_csv_line(line: &str) -> Vec<String> {
+  let mut fields = Vec::new();
+  let mut field = String::new();
+  let mut quoted = false;
+  let mut chars = line.trim_end_matches('\r').chars().peekable();
+
+  while let Some(c) = chars.next() {
+    match c {
+      '"' if quoted && chars.peek() == Some(&'"') => {
+        field.push('"');
+        chars.next();
+      }
+      '"' => quoted = !quoted,
+      ',' if !quoted => fields.push(std::mem::take(&mut field)),
+      c => field.push(c),
+    }
+  }
+  fields.push(field);
+  fields
+}
+
+/// Registers every parsed row with at most `concurrency` registrations in flight, using a
+/// client from `client_for` per row, and reports the outcome of each row in input order.
+pub async fn register_all<T, F>(
+  rows: Vec<(usize, Result<ImportRow, UnparsedRow>)>,
+  concurrency: usize,
+  client_for: F,
+) -> ImportReport
+where
+  T: CustomerRegistration,
+  F: Fn(&ImportRow) -> Result<T, String>,
+{
+  let mut skipped = 0;
+  let mut pending = Vec::with_capacity(rows.len());
+  for (line, row) in rows {
+    match row {
+      Ok(ImportRow {
+        status: Some(ImportStatus::Registered),
+        ..
+      }) => skipped += 1,
+      row => pending.push((line, row)),
+    }
+  }
+
+  info!(
+    "Importing {} customers, skipping {} already registered",
+    pending.len(),
+    skipped
+  );
+
+  let client_for = &client_for;
+  let mut results: Vec<ImportRowResult> = stream::iter(pending)
+    .map(|(line, row)| async move {
+      let row = match row {
+        Ok(row) => row,
+        Err(unparsed) => {
+          return ImportRowResult {
+            line,
+            row: None,
+            raw: Some(unparsed.raw),
+            status: ImportStatus::Failed,
+            error: Some(unparsed.error),
+          }
+        }
+      };
+
+      let outcome = match (parse_handle_type(&row.handle_type), client_for(&row)) {
+        (Ok(handle_type), Ok(versa_client)) => crate::customer_registration::register_customer(
+          versa_client,
+          row.handle.clone(),
+          handle_type,
+          row.receiver_client_id.clone(),
+        )
+        .await
+        .map_err(|e| format!("Registration failed: {:?}", e)),
+        (Err(e), _) | (_, Err(e)) => Err(e),
+      };
+
+      match outcome {
+        Ok(_) => ImportRowResult {
+          line,
+          row: Some(row),
+          raw: None,
+          status: ImportStatus::Registered,
+          error: None,
+        },
+        Err(e) => {
+          info!("Failed to register customer on line {}: {}", line, e);
+          ImportRowResult {
+            line,
+            row: Some(row),
+            raw: None,
+            status: ImportStatus::Failed,
+            error: Some(e),
+          }
+        }
+      }
+    })
+    .buffer_unordered(concurrency.max(1))
+    .collect()
+    .await;
+
+  results.sort_by_key(|result| result.line);
+
+  let registered = results
+    .iter()
+    .filter(|result| matches!(result.status, ImportStatus::Registered))
+    .count();
+
+  ImportReport {
+    registered,
+    failed: results.len() - registered,
+    skipped,
+    results,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  #[test]
+  fn test_csv_rows_should_parse_with_quoted_fields() {
+    let input = "handle,handle_type,receiver_client_id\n\
+                 \"jane@example.com\",customer_email,versa_cid_123\n\
+                 \n\
+                 \"a,b\",customer_email,\n\
+                 missing_fields\n";
+
+    let rows = parse_rows(input, true);
+    assert_eq!(rows.len(), 3);
+
+    let (line, row) = &rows[0];
+    let row = row.as_ref().unwrap();
+    assert_eq!(*line, 2);
+    assert_eq!(row.handle, "jane@example.com");
+    assert_eq!(row.handle_type, "customer_email");
+    assert!(parse_handle_type(&row.handle_type).is_ok());
+    assert_eq!(row.receiver_client_id.as_deref(), Some("versa_cid_123"));
+
+    let (line, row) = &rows[1];
+    assert_eq!(*line, 4);
+    assert_eq!(row.as_ref().unwrap().handle, "a,b");
+    assert_eq!(row.as_ref().unwrap().receiver_client_id, None);
+
+    assert_eq!(
+      rows[2].1.as_ref().unwrap_err(),
+      &UnparsedRow {
+        raw: "missing_fields".into(),
+        error: "Expected 3 fields, found 1".into(),
+      }
+    );
+  }
+
+  #[test]
+  fn test_report_rows_should_parse_as_import_rows() {
+    let input = "{\"line\":2,\"handle\":\"jane@example.com\",\"handle_type\":\"customer_email\",\"status\":\"registered\"}\n\
+                 {\"line\":3,\"handle\":\"joe@example.com\",\"handle_type\":\"customer_email\",\"status\":\"failed\",\"error\":\"Registration failed\"}\n";
+
+    let rows = parse_rows(input, false);
+    let statuses: Vec<_> = rows
+      .iter()
+      .map(|(_, row)| row.as_ref().unwrap().status.clone())
+      .collect();
+    assert!(matches!(statuses[0], Some(ImportStatus::Registered)));
+    assert!(matches!(statuses[1], Some(ImportStatus::Failed)));
+  }
+
+  /// Accepts every registration
+  struct TestRegistry;
+
+  impl CustomerRegistration for TestRegistry {
+    async fn register_customer_reference(
+      &self,
+      _customer_reference: versa::protocol::customer_registration::CustomerRef,
+    ) -> Result<(), versa::client::ClientError> {
+      Ok(())
+    }
+
+    async fn deregister_customer_reference(
+      &self,
+      _customer_reference: versa::protocol::customer_registration::CustomerRef,
+    ) -> Result<(), versa::client::ClientError> {
+      Ok(())
+    }
+  }
+
+  #[tokio::test]
+  async fn test_report_should_keep_the_raw_line_of_unparsed_rows() {
+    let input = "{\"handle\":\"jane@example.com\",\"handle_type\":\"customer_email\"}\n\
+                 {\"handle\":\"jane@example.com\"\n";
+
+    let report = register_all(parse_rows(input, false), 2, |_| Ok(TestRegistry)).await;
+    assert_eq!(report.registered, 1);
+    assert_eq!(report.failed, 1);
+
+    let lines: Vec<serde_json::Value> = report
+      .to_jsonl()
+      .lines()
+      .map(|line| serde_json::from_str(line).unwrap())
+      .collect();
+    assert_eq!(lines[0]["handle"], "jane@example.com");
+    assert_eq!(lines[0].get("raw"), None);
+    assert_eq!(lines[1]["line"], 2);
+    assert_eq!(lines[1]["status"], "failed");
+    assert_eq!(lines[1]["raw"], "{\"handle\":\"jane@example.com\"");
+  }
+}
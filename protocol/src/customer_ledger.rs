@@ -0,0 +1,223 @@
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, Row};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// Local record of the customer handles this client has registered and deregistered, so
+/// enrollment can be answered without asking the registry. Deregistered handles are kept
+/// with the time they were deregistered.
+pub struct CustomerLedger {
+  conn: Mutex<Connection>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CustomerEntry {
+  pub handle: String,
+  pub handle_type: String,
+  pub receiver_client_id: Option<String>,
+  pub registered: bool,
+  pub registered_at: i64,
+  pub deregistered_at: Option<i64>,
+  pub updated_at: i64,
+}
+
+/// Query parameters for listing ledger entries
+#[derive(Debug, Default, Deserialize)]
+pub struct CustomerQuery {
+  pub handle: Option<String>,
+  #[serde(default)]
+  pub include_deregistered: bool,
+}
+
+impl CustomerLedger {
+  pub fn open(path: &str) -> rusqlite::Result<Self> {
+    let conn = Connection::open(path)?;
+    // receiver_client_id is stored as '' when absent so that it can be part of the key
+    conn.execute_batch(
+      "CREATE TABLE IF NOT EXISTS customers (
+        handle TEXT NOT NULL,
+        handle_type TEXT NOT NULL,
+        receiver_client_id TEXT NOT NULL DEFAULT '',
+        registered_at INTEGER NOT NULL,
+        deregistered_at INTEGER,
+        updated_at INTEGER NOT NULL,
+        PRIMARY KEY (handle, handle_type, receiver_client_id)
+      );",
+    )?;
+    Ok(Self {
+      conn: Mutex::new(conn),
+    })
+  }
+
+  pub fn record_registered(
+    &self,
+    handle: &str,
+    handle_type: &str,
+    receiver_client_id: Option<&str>,
+  ) -> rusqlite::Result<()> {
+    let now = util::unix_timestamp();
+    let conn = self.conn.lock().unwrap();
+    conn.execute(
+      "INSERT INTO customers (handle, handle_type, receiver_client_id, registered_at, updated_at)
+       VALUES (?1, ?2, ?3, ?4, ?4)
+       ON CONFLICT (handle, handle_type, receiver_client_id) DO UPDATE SET
+         registered_at = excluded.registered_at,
+         deregistered_at = NULL,
+         updated_at = excluded.updated_at",
+      params![
+        handle,
+        handle_type,
+        receiver_client_id.unwrap_or_default(),
+        now
+      ],
+    )?;
+    Ok(())
+  }
+
+  /// Marks the handle deregistered, keeping a record of it even if it was never
+  /// registered through this client
+  pub fn record_deregistered(
+    &self,
+    handle: &str,
+    handle_type: &str,
+    receiver_client_id: Option<&str>,
+  ) -> rusqlite::Result<()> {
+    let now = util::unix_timestamp();
+    let conn = self.conn.lock().unwrap();
+    conn.execute(
+      "INSERT INTO customers (handle, handle_type, receiver_client_id, registered_at, deregistered_at, updated_at)
+       VALUES (?1, ?2, ?3, ?4, ?4, ?4)
+       ON CONFLICT (handle, handle_type, receiver_client_id) DO UPDATE SET
+         deregistered_at = excluded.deregistered_at,
+         updated_at = excluded.updated_at",
+      params![
+        handle,
+        handle_type,
+        receiver_client_id.unwrap_or_default(),
+        now
+      ],
+    )?;
+    Ok(())
+  }
+
+  /// Lists entries, optionally only those for `handle`, most recently updated first
+  pub fn list(
+    &self,
+    handle: Option<&str>,
+    include_deregistered: bool,
+  ) -> rusqlite::Result<Vec<CustomerEntry>> {
+    let conn = self.conn.lock().unwrap();
+    let mut stmt = conn.prepare(
+      "SELECT handle, handle_type, receiver_client_id, registered_at, deregistered_at, updated_at
+       FROM customers
+       WHERE (?1 IS NULL OR handle = ?1) AND (?2 OR deregistered_at IS NULL)
+       ORDER BY updated_at DESC, handle",
+    )?;
+    let rows = stmt.query_map(params![handle, include_deregistered], entry_from_row)?;
+    rows.collect()
+  }
+}
+
+/// Lists the entries matching a `GET /customer` query
+pub fn list_customers(
+  ledger: &CustomerLedger,
+  query: &CustomerQuery,
+) -> Result<Vec<CustomerEntry>, (http::StatusCode, String)> {
+  ledger
+    .list(query.handle.as_deref(), query.include_deregistered)
+    .map_err(|e| {
+      info!("Failed to list customers: {:?}", e);
+      (
+        http::StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to list customers".to_string(),
+      )
+    })
+}
+
+/// Every ledger entry for the handle, including deregistrations
+pub fn lookup_customer(
+  ledger: &CustomerLedger,
+  handle: &str,
+) -> Result<Vec<CustomerEntry>, (http::StatusCode, String)> {
+  let entries = ledger.list(Some(handle), true).map_err(|e| {
+    info!("Failed to look up customer: {:?}", e);
+    (
+      http::StatusCode::INTERNAL_SERVER_ERROR,
+      "Failed to look up customer".to_string(),
+    )
+  })?;
+  if entries.is_empty() {
+    return Err((
+      http::StatusCode::NOT_FOUND,
+      format!("No customer found with handle {}", handle),
+    ));
+  }
+  Ok(entries)
+}
+
+fn entry_from_row(row: &Row) -> rusqlite::Result<CustomerEntry> {
+  let receiver_client_id: String = row.get(2)?;
+  let deregistered_at: Option<i64> = row.get(4)?;
+  Ok(CustomerEntry {
+    handle: row.get(0)?,
+    handle_type: row.get(1)?,
+    receiver_client_id: Some(receiver_client_id).filter(|id| !id.is_empty()),
+    registered: deregistered_at.is_none(),
+    registered_at: row.get(3)?,
+    deregistered_at,
+    updated_at: row.get(5)?,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  #[test]
+  fn test_deregistered_handle_should_be_kept_with_timestamp() {
+    let ledger = CustomerLedger::open(":memory:").unwrap();
+    let handle_type = "customer_email";
+
+    ledger
+      .record_registered("jane@example.com", handle_type, Some("versa_cid_123"))
+      .unwrap();
+    ledger
+      .record_registered("joe@example.com", handle_type, None)
+      .unwrap();
+    ledger
+      .record_deregistered("joe@example.com", handle_type, None)
+      .unwrap();
+
+    let active = ledger.list(None, false).unwrap();
+    assert_eq!(active.len(), 1);
+    assert_eq!(active[0].handle, "jane@example.com");
+    assert_eq!(
+      active[0].receiver_client_id.as_deref(),
+      Some("versa_cid_123")
+    );
+
+    let joe = ledger.list(Some("joe@example.com"), true).unwrap();
+    assert_eq!(joe.len(), 1);
+    assert!(!joe[0].registered);
+    assert!(joe[0].deregistered_at.is_some());
+    assert_eq!(joe[0].receiver_client_id, None);
+  }
+
+  #[test]
+  fn test_lookup_of_unknown_handle_should_be_not_found() {
+    let ledger = CustomerLedger::open(":memory:").unwrap();
+    ledger
+      .record_registered("jane@example.com", "customer_email", None)
+      .unwrap();
+
+    assert_eq!(
+      lookup_customer(&ledger, "jane@example.com").unwrap().len(),
+      1
+    );
+    let (status, _) = lookup_customer(&ledger, "joe@example.com").unwrap_err();
+    assert_eq!(status, http::StatusCode::NOT_FOUND);
+  }
+}
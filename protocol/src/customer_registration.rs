@@ -3,6 +3,20 @@ use versa::{
   protocol::customer_registration::{CustomerRef, HandleType},
 };
 
+/// Parses a handle type from its snake_case name, e.g. `customer_email`
+pub fn parse_handle_type(name: &str) -> Result<HandleType, String> {
+  serde_json::from_value(serde_json::Value::String(name.to_string()))
+    .map_err(|_| format!("Unknown handle_type: {}", name))
+}
+
+/// The snake_case name of a handle type, as it is stored in the customer ledger
+pub fn handle_type_name(handle_type: &HandleType) -> String {
+  match serde_json::to_value(handle_type) {
+    Ok(serde_json::Value::String(name)) => name,
+    _ => format!("{:?}", handle_type),
+  }
+}
+
 pub async fn register_customer<T>(
   versa_client: T,
   handle: String,
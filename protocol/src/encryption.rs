@@ -0,0 +1,107 @@
+use aes_gcm_siv::aead::{Aead, KeyInit};
+use aes_gcm_siv::{Aes256GcmSiv, Nonce};
+use base64::prelude::*;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::model::Envelope;
+
+/// Generates a random base64-encoded AES-256 key
+pub fn generate_key() -> String {
+  BASE64_STANDARD.encode(rand::random::<[u8; 32]>())
+}
+
+/// Encrypts the canonical JSON form of `data` with AES-256-GCM-SIV under a fresh random
+/// nonce, the same way the `versa` crate encrypts envelopes for receivers
+pub fn encrypt_envelope<T: Serialize>(data: &T, key: &str) -> Result<Envelope, String> {
+  let canonical = canonicalize(data)?;
+  let cipher = cipher_for(key)?;
+
+  let nonce = rand::random::<[u8; 12]>();
+  let encrypted = cipher
+    .encrypt(Nonce::from_slice(&nonce), canonical.as_bytes())
+    .map_err(|e| format!("Failed to encrypt data: {}", e))?;
+
+  Ok(Envelope {
+    encrypted: BASE64_STANDARD.encode(encrypted),
+    nonce: BASE64_STANDARD.encode(nonce),
+  })
+}
+
+/// Hex-encoded SHA-1 digest of the canonical JSON form of `data`
+pub fn canonical_digest<T: Serialize>(data: &T) -> Result<String, String> {
+  use sha1::Digest;
+
+  let canonical = canonicalize(data)?;
+  Ok(format!("{:x}", sha1::Sha1::digest(canonical.as_bytes())))
+}
+
+fn canonicalize<T: Serialize>(data: &T) -> Result<String, String> {
+  json_canon::to_string(&json!(data)).map_err(|e| format!("Failed to canonicalize data: {}", e))
+}
+
+pub fn decrypt_envelope(envelope: &Envelope, key: &str) -> Result<serde_json::Value, String> {
+  let cipher = cipher_for(key)?;
+  let nonce = BASE64_STANDARD
+    .decode(&envelope.nonce)
+    .map_err(|e| format!("Invalid nonce: {}", e))?;
+  let encrypted = BASE64_STANDARD
+    .decode(&envelope.encrypted)
+    .map_err(|e| format!("Invalid ciphertext: {}", e))?;
+  if nonce.len() != 12 {
+    return Err("Invalid nonce length".to_string());
+  }
+
+  let decrypted = cipher
+    .decrypt(Nonce::from_slice(&nonce), encrypted.as_ref())
+    .map_err(|e| format!("Failed to decrypt data: {}", e))?;
+  serde_json::from_slice(&decrypted).map_err(|e| format!("Failed to parse data: {}", e))
+}
+
+fn cipher_for(key: &str) -> Result<Aes256GcmSiv, String> {
+  let key_bytes = BASE64_STANDARD
+    .decode(key)
+    .map_err(|e| format!("Invalid encryption key: {}", e))?;
+  Aes256GcmSiv::new_from_slice(&key_bytes).map_err(|_| "Invalid encryption key length".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  #[test]
+  fn test_encrypted_envelope_should_decrypt_to_original_data() {
+    let key = generate_key();
+    let data = serde_json::json!({ "schema_version": "1.10.0", "header": { "total": 2212 } });
+
+    let envelope = encrypt_envelope(&data, &key).unwrap();
+    assert_eq!(decrypt_envelope(&envelope, &key).unwrap(), data);
+    assert!(decrypt_envelope(&envelope, &generate_key()).is_err());
+  }
+
+  #[test]
+  fn test_envelope_should_hold_canonical_json() {
+    let key = generate_key();
+    let data = serde_json::json!({ "qux": null, "foo": "bar", "baz": 123 });
+
+    let envelope = encrypt_envelope(&data, &key).unwrap();
+    let cipher = cipher_for(&key).unwrap();
+    let nonce = BASE64_STANDARD.decode(&envelope.nonce).unwrap();
+    let decrypted = cipher
+      .decrypt(
+        Nonce::from_slice(&nonce),
+        BASE64_STANDARD
+          .decode(&envelope.encrypted)
+          .unwrap()
+          .as_ref(),
+      )
+      .unwrap();
+    assert_eq!(nonce.len(), 12);
+    assert_eq!(
+      String::from_utf8(decrypted).unwrap(),
+      "{\"baz\":123,\"foo\":\"bar\",\"qux\":null}"
+    );
+  }
+}
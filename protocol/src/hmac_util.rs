@@ -8,3 +8,74 @@ pub async fn generate_token(body: bytes::Bytes, secret: String) -> String {
   let encoded = BASE64_STANDARD.encode(&code_bytes.to_vec());
   encoded
 }
+
+fn timestamped_mac(timestamp: &str, body: &[u8], secret: &str) -> hmac::Hmac<sha1::Sha1> {
+  let mut mac = hmac::Hmac::<sha1::Sha1>::new_from_slice(secret.as_bytes()).unwrap();
+  mac.update(timestamp.as_bytes());
+  mac.update(b".");
+  mac.update(body);
+  mac
+}
+
+/// Same as [`generate_token`], signing `{timestamp}.{body}` so that the signature cannot
+/// be replayed with a different timestamp. Sent in the `X-Request-Signature` header
+/// alongside the timestamp in `X-Request-Timestamp`.
+pub fn generate_timestamped_token(timestamp: i64, body: &[u8], secret: &str) -> String {
+  let code_bytes = timestamped_mac(&timestamp.to_string(), body, secret)
+    .finalize()
+    .into_bytes();
+  BASE64_STANDARD.encode(code_bytes)
+}
+
+/// Verifies the `X-Request-Signature` and `X-Request-Timestamp` headers of a request
+/// signed with [`generate_timestamped_token`], rejecting timestamps more than
+/// `tolerance_secs` away from `now`
+pub fn verify_timestamped_token(
+  body: &[u8],
+  timestamp: &str,
+  token: &str,
+  secret: &str,
+  tolerance_secs: i64,
+  now: i64,
+) -> Result<(), String> {
+  let parsed: i64 = timestamp
+    .parse()
+    .map_err(|_| format!("Invalid request timestamp: {}", timestamp))?;
+  if (now - parsed).abs() > tolerance_secs {
+    return Err(format!(
+      "Request timestamp {} is outside the tolerance of {}s",
+      parsed, tolerance_secs
+    ));
+  }
+
+  let signature = BASE64_STANDARD
+    .decode(token)
+    .map_err(|_| "Malformed request signature".to_string())?;
+  timestamped_mac(timestamp, body, secret)
+    .verify_slice(&signature)
+    .map_err(|_| "Failed to verify request signature".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  #[test]
+  fn test_timestamped_token_should_verify_within_tolerance() {
+    let body = br#"{"receipt_id":"rct_123"}"#;
+    let token = generate_timestamped_token(1000, body, "secret");
+
+    assert_eq!(
+      verify_timestamped_token(body, "1000", &token, "secret", 300, 1200),
+      Ok(())
+    );
+    assert!(verify_timestamped_token(body, "1000", &token, "other", 300, 1200).is_err());
+    assert!(verify_timestamped_token(body, "1001", &token, "secret", 300, 1200).is_err());
+    assert!(verify_timestamped_token(b"{}", "1000", &token, "secret", 300, 1200).is_err());
+    assert!(verify_timestamped_token(body, "1000", &token, "secret", 300, 1400).is_err());
+    assert!(verify_timestamped_token(body, "1000", &token, "secret", 300, 600).is_err());
+    assert!(verify_timestamped_token(body, "abc", &token, "secret", 300, 1200).is_err());
+  }
+}
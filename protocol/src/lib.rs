@@ -1,19 +1,28 @@
+pub mod consistency;
+pub mod customer_import;
+pub mod customer_ledger;
 pub mod customer_registration;
+pub mod encryption;
 pub mod hmac_util;
 pub mod model;
+pub mod receipt_builder;
+pub mod schema;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use versa::protocol::{Org, TransactionHandles, VersaMode};
 
 use tracing::info;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct ReceiverInfo {
   pub client_id: String,
   pub receiver: Option<Org>,
+  /// Webhook address of the receiver, when the registry includes it
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub address: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct CheckRegistryResponse {
   pub mode: VersaMode,
   pub receivers: Vec<ReceiverInfo>,
@@ -0,0 +1,589 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Schema versions the builder emits receipts for
+pub const SCHEMA_VERSIONS: &[&str] = &["1.11.0", "2.0.0"];
+
+/// Receipt shapes that differ between the supported schema versions
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Shape {
+  V1_11,
+  V2_0,
+}
+
+impl Shape {
+  fn for_version(schema_version: &str) -> Result<Self, String> {
+    match schema_version {
+      "1.11.0" => Ok(Shape::V1_11),
+      "2.0.0" => Ok(Shape::V2_0),
+      _ => Err(format!(
+        "The receipt builder cannot emit schema_version {}, expected one of: {}",
+        schema_version,
+        SCHEMA_VERSIONS.join(", ")
+      )),
+    }
+  }
+}
+
+/// Builds receipts from typed parts, computing line item subtotals, tax amounts and the
+/// header `subtotal`, `total` and `paid` so that they always agree with each other.
+///
+/// All amounts are integers in the currency's minor unit, e.g. cents.
+///
+/// ```ignore
+/// let receipt = ReceiptBuilder::new("usd", 1713295619)
+///   .invoice_number("INV-0001")
+///   .line_item(LineItem::new("Widget", 10.0, 178).tax(Tax::rate("GST", 0.0875)))
+///   .payment(Payment::card(1936, 1713295619, "4886", "mastercard"))
+///   .build("2.0.0")?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct ReceiptBuilder {
+  currency: String,
+  invoiced_at: i64,
+  invoice_number: Option<String>,
+  mcc: Option<String>,
+  customer: Option<Customer>,
+  line_items: Vec<LineItem>,
+  invoice_level_adjustments: Vec<Adjustment>,
+  payments: Vec<Payment>,
+  actions: Vec<Action>,
+  supplemental_text: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Customer {
+  pub name: String,
+  pub email: Option<String>,
+  pub phone: Option<String>,
+  /// Only emitted for schema 2.0.0, which added it
+  pub website: Option<String>,
+}
+
+impl Customer {
+  fn to_json(&self, shape: Shape) -> Value {
+    match shape {
+      Shape::V1_11 => json!({
+        "name": self.name,
+        "email": self.email,
+        "address": null,
+        "phone": self.phone,
+        "metadata": [],
+      }),
+      Shape::V2_0 => json!({
+        "name": self.name,
+        "email": self.email,
+        "website": self.website,
+        "address": null,
+        "phone": self.phone,
+        "metadata": [],
+      }),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Action {
+  pub name: String,
+  pub url: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TaxAmount {
+  Fixed(i64),
+  Rate(f64),
+}
+
+/// A tax on a line item, either a fixed amount or a rate applied to the line item's
+/// subtotal after its adjustments
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tax {
+  name: String,
+  amount: TaxAmount,
+  rate: Option<f64>,
+}
+
+impl Tax {
+  pub fn fixed(name: &str, amount: i64) -> Self {
+    Self {
+      name: name.to_string(),
+      amount: TaxAmount::Fixed(amount),
+      rate: None,
+    }
+  }
+
+  pub fn rate(name: &str, rate: f64) -> Self {
+    Self {
+      name: name.to_string(),
+      amount: TaxAmount::Rate(rate),
+      rate: Some(rate),
+    }
+  }
+
+  /// A fixed amount that was computed elsewhere at the given rate
+  pub fn fixed_at_rate(name: &str, amount: i64, rate: f64) -> Self {
+    Self {
+      name: name.to_string(),
+      amount: TaxAmount::Fixed(amount),
+      rate: Some(rate),
+    }
+  }
+
+  fn amount_on(&self, base: i64) -> i64 {
+    match self.amount {
+      TaxAmount::Fixed(amount) => amount,
+      TaxAmount::Rate(rate) => (base as f64 * rate).round() as i64,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdjustmentType {
+  Discount,
+  Tip,
+  Fee,
+  Other,
+}
+
+/// An amount added to a line item or the invoice. Discounts are negative.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Adjustment {
+  pub adjustment_type: AdjustmentType,
+  pub amount: i64,
+  pub name: Option<String>,
+  pub rate: Option<f64>,
+}
+
+impl Adjustment {
+  pub fn new(adjustment_type: AdjustmentType, name: &str, amount: i64) -> Self {
+    Self {
+      adjustment_type,
+      amount,
+      name: Some(name.to_string()),
+      rate: None,
+    }
+  }
+
+  /// A discount of `amount`, given as a positive number
+  pub fn discount(name: &str, amount: i64) -> Self {
+    Self::new(AdjustmentType::Discount, name, -amount.abs())
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineItem {
+  description: String,
+  quantity: f64,
+  unit_cost: i64,
+  subtotal: Option<i64>,
+  unit: Option<String>,
+  date: Option<String>,
+  url: Option<String>,
+  taxes: Vec<Tax>,
+  adjustments: Vec<Adjustment>,
+}
+
+impl LineItem {
+  pub fn new(description: &str, quantity: f64, unit_cost: i64) -> Self {
+    Self {
+      description: description.to_string(),
+      quantity,
+      unit_cost,
+      subtotal: None,
+      unit: None,
+      date: None,
+      url: None,
+      taxes: Vec::new(),
+      adjustments: Vec::new(),
+    }
+  }
+
+  /// Overrides the subtotal otherwise computed as `quantity * unit_cost`, for sources
+  /// that round line amounts differently
+  pub fn subtotal(mut self, subtotal: i64) -> Self {
+    self.subtotal = Some(subtotal);
+    self
+  }
+
+  pub fn unit(mut self, unit: &str) -> Self {
+    self.unit = Some(unit.to_string());
+    self
+  }
+
+  /// The date of the line item, as `YYYY-MM-DD`
+  pub fn date(mut self, date: &str) -> Self {
+    self.date = Some(date.to_string());
+    self
+  }
+
+  pub fn url(mut self, url: &str) -> Self {
+    self.url = Some(url.to_string());
+    self
+  }
+
+  pub fn tax(mut self, tax: Tax) -> Self {
+    self.taxes.push(tax);
+    self
+  }
+
+  pub fn adjustment(mut self, adjustment: Adjustment) -> Self {
+    self.adjustments.push(adjustment);
+    self
+  }
+
+  fn computed_subtotal(&self) -> i64 {
+    self
+      .subtotal
+      .unwrap_or_else(|| (self.quantity * self.unit_cost as f64).round() as i64)
+  }
+
+  fn adjustments_total(&self) -> i64 {
+    self.adjustments.iter().map(|adj| adj.amount).sum()
+  }
+
+  fn tax_amounts(&self) -> Vec<i64> {
+    let base = self.computed_subtotal() + self.adjustments_total();
+    self.taxes.iter().map(|tax| tax.amount_on(base)).collect()
+  }
+
+  /// Subtotal after adjustments and taxes
+  fn total(&self) -> i64 {
+    self.computed_subtotal() + self.adjustments_total() + self.tax_amounts().iter().sum::<i64>()
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum PaymentMethod {
+  Card {
+    last_four: Option<String>,
+    network: Option<String>,
+  },
+  Ach {
+    routing_number: Option<String>,
+  },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Payment {
+  amount: i64,
+  paid_at: i64,
+  method: PaymentMethod,
+}
+
+impl Payment {
+  pub fn card(amount: i64, paid_at: i64, last_four: &str, network: &str) -> Self {
+    Self {
+      amount,
+      paid_at,
+      method: PaymentMethod::Card {
+        last_four: Some(last_four.to_string()),
+        network: Some(network.to_string()),
+      },
+    }
+  }
+
+  /// A card payment whose card details are not known
+  pub fn unknown_card(amount: i64, paid_at: i64) -> Self {
+    Self {
+      amount,
+      paid_at,
+      method: PaymentMethod::Card {
+        last_four: None,
+        network: None,
+      },
+    }
+  }
+
+  pub fn ach(amount: i64, paid_at: i64, routing_number: Option<&str>) -> Self {
+    Self {
+      amount,
+      paid_at,
+      method: PaymentMethod::Ach {
+        routing_number: routing_number.map(String::from),
+      },
+    }
+  }
+
+  /// The card or bank details are omitted when they are not known, as the schemas require
+  /// the last four digits of a card and the routing number of a bank account
+  fn to_json(&self) -> Value {
+    let (payment_type, card_payment, ach_payment) = match &self.method {
+      PaymentMethod::Card { last_four, network } => (
+        "card",
+        last_four
+          .as_ref()
+          .map(|last_four| json!({ "last_four": last_four, "network": network })),
+        None,
+      ),
+      PaymentMethod::Ach { routing_number } => (
+        "ach",
+        None,
+        routing_number
+          .as_ref()
+          .map(|routing_number| json!({ "routing_number": routing_number })),
+      ),
+    };
+    json!({
+      "amount": self.amount,
+      "paid_at": self.paid_at,
+      "payment_type": payment_type,
+      "card_payment": card_payment,
+      "ach_payment": ach_payment,
+    })
+  }
+}
+
+/// The computed header amounts of a receipt
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReceiptTotals {
+  pub subtotal: i64,
+  pub total: i64,
+  pub paid: i64,
+}
+
+impl ReceiptBuilder {
+  pub fn new(currency: &str, invoiced_at: i64) -> Self {
+    Self {
+      currency: currency.to_lowercase(),
+      invoiced_at,
+      invoice_number: None,
+      mcc: None,
+      customer: None,
+      line_items: Vec::new(),
+      invoice_level_adjustments: Vec::new(),
+      payments: Vec::new(),
+      actions: Vec::new(),
+      supplemental_text: None,
+    }
+  }
+
+  pub fn invoice_number(mut self, invoice_number: &str) -> Self {
+    self.invoice_number = Some(invoice_number.to_string());
+    self
+  }
+
+  pub fn mcc(mut self, mcc: &str) -> Self {
+    self.mcc = Some(mcc.to_string());
+    self
+  }
+
+  pub fn customer(mut self, customer: Customer) -> Self {
+    self.customer = Some(customer);
+    self
+  }
+
+  pub fn line_item(mut self, line_item: LineItem) -> Self {
+    self.line_items.push(line_item);
+    self
+  }
+
+  pub fn invoice_adjustment(mut self, adjustment: Adjustment) -> Self {
+    self.invoice_level_adjustments.push(adjustment);
+    self
+  }
+
+  pub fn payment(mut self, payment: Payment) -> Self {
+    self.payments.push(payment);
+    self
+  }
+
+  pub fn action(mut self, name: &str, url: &str) -> Self {
+    self.actions.push(Action {
+      name: name.to_string(),
+      url: url.to_string(),
+    });
+    self
+  }
+
+  pub fn supplemental_text(mut self, text: &str) -> Self {
+    self.supplemental_text = Some(text.to_string());
+    self
+  }
+
+  pub fn totals(&self) -> ReceiptTotals {
+    let subtotal = self
+      .line_items
+      .iter()
+      .map(LineItem::computed_subtotal)
+      .sum();
+    let total = self.line_items.iter().map(LineItem::total).sum::<i64>()
+      + self
+        .invoice_level_adjustments
+        .iter()
+        .map(|adj| adj.amount)
+        .sum::<i64>();
+    let paid = self.payments.iter().map(|payment| payment.amount).sum();
+    ReceiptTotals {
+      subtotal,
+      total,
+      paid,
+    }
+  }
+
+  /// Emits the receipt JSON for `schema_version`, which must be one of the builder's
+  /// [`SCHEMA_VERSIONS`]
+  pub fn build(&self, schema_version: &str) -> Result<Value, String> {
+    let shape = Shape::for_version(schema_version)?;
+    let totals = self.totals();
+
+    let items: Vec<Value> = self
+      .line_items
+      .iter()
+      .map(|item| {
+        let taxes: Vec<Value> = item
+          .taxes
+          .iter()
+          .zip(item.tax_amounts())
+          .map(|(tax, amount)| {
+            json!({
+              "amount": amount,
+              "rate": tax.rate,
+              "name": tax.name,
+            })
+          })
+          .collect();
+        json!({
+          "description": item.description,
+          "amount": item.computed_subtotal(),
+          "quantity": item.quantity,
+          "unit_cost": item.unit_cost,
+          "unit": item.unit,
+          "taxes": taxes,
+          "adjustments": item.adjustments,
+          "metadata": [],
+          "product_image_asset_id": null,
+          "date": item.date,
+          "url": item.url,
+        })
+      })
+      .collect();
+
+    Ok(json!({
+      "schema_version": schema_version,
+      "header": {
+        "invoice_number": self.invoice_number,
+        "currency": self.currency,
+        "total": totals.total,
+        "subtotal": totals.subtotal,
+        "paid": totals.paid,
+        "invoiced_at": self.invoiced_at,
+        "mcc": self.mcc,
+        "third_party": null,
+        "customer": self.customer.as_ref().map(|customer| customer.to_json(shape)),
+        "location": null,
+        "invoice_asset_id": null,
+        "receipt_asset_id": null,
+      },
+      "itemization": {
+        "general": {
+          "items": items,
+          "invoice_level_adjustments": self.invoice_level_adjustments,
+        },
+        "lodging": null,
+        "ecommerce": null,
+        "car_rental": null,
+        "transit_route": null,
+        "subscription": null,
+        "flight": null,
+      },
+      "footer": {
+        "actions": self.actions,
+        "supplemental_text": self.supplemental_text,
+      },
+      "payments": self.payments.iter().map(Payment::to_json).collect::<Vec<_>>(),
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  #[test]
+  fn test_builder_should_compute_consistent_totals() {
+    let builder = ReceiptBuilder::new("USD", 1713295619)
+      .invoice_number("INV-0001")
+      .line_item(
+        LineItem::new("Widget", 10.0, 178)
+          .adjustment(Adjustment::discount("Bulk discount", 80))
+          .tax(Tax::rate("GST", 0.0875)),
+      )
+      .line_item(LineItem::new("Gift wrap", 1.0, 250).tax(Tax::fixed("Wrap fee tax", 20)))
+      .invoice_adjustment(Adjustment::new(AdjustmentType::Tip, "Tip", 300))
+      .payment(Payment::card(2000, 1713295619, "4886", "mastercard"))
+      .payment(Payment::ach(601, 1713295620, None));
+
+    // Widget: 1780 - 80 = 1700, plus 8.75% tax of 148.75 rounded to 149
+    // Gift wrap: 250 plus 20 tax, and a 300 tip on the invoice
+    assert_eq!(
+      builder.totals(),
+      ReceiptTotals {
+        subtotal: 2030,
+        total: 1700 + 149 + 250 + 20 + 300,
+        paid: 2601,
+      }
+    );
+
+    let receipt = builder.build("2.0.0").unwrap();
+    assert_eq!(receipt["header"]["currency"], "usd");
+    assert_eq!(receipt["header"]["total"], 2419);
+    let widget = &receipt["itemization"]["general"]["items"][0];
+    assert_eq!(widget["amount"], 1780);
+    assert_eq!(widget["taxes"][0]["amount"], 149);
+    assert_eq!(widget["adjustments"][0]["amount"], -80);
+    assert_eq!(receipt["payments"][1]["payment_type"], "ach");
+    assert_eq!(receipt["payments"][1]["ach_payment"], Value::Null);
+
+    assert!(builder.build("0.1.0").is_err());
+    assert!(builder.build("1.10.0").is_err());
+  }
+
+  fn full_receipt() -> ReceiptBuilder {
+    ReceiptBuilder::new("usd", 1713295619)
+      .invoice_number("INV-0001")
+      .mcc("5812")
+      .customer(Customer {
+        name: "Jane Doe".into(),
+        email: Some("jane@example.com".into()),
+        phone: Some("+14155550100".into()),
+        website: Some("example.com".into()),
+      })
+      .line_item(
+        LineItem::new("Widget", 10.0, 178)
+          .unit("each")
+          .date("2024-04-16")
+          .url("https://example.com/widget")
+          .adjustment(Adjustment::discount("Bulk discount", 80))
+          .tax(Tax::rate("GST", 0.0875)),
+      )
+      .invoice_adjustment(Adjustment::new(AdjustmentType::Tip, "Tip", 300))
+      .payment(Payment::card(1000, 1713295619, "4886", "mastercard"))
+      .payment(Payment::unknown_card(500, 1713295619))
+      .payment(Payment::ach(649, 1713295620, Some("011000015")))
+      .action("View invoice", "https://example.com/invoice")
+      .supplemental_text("Thank you")
+  }
+
+  #[tokio::test]
+  async fn test_built_receipt_should_match_the_schema_of_each_version() {
+    let builder = full_receipt();
+    for schema_version in SCHEMA_VERSIONS {
+      let receipt = builder.build(schema_version).unwrap();
+      let result = crate::schema::validate_all(
+        &versa::protocol::webhook::TransactionEvent::Receipt,
+        schema_version,
+        &receipt,
+      )
+      .await;
+      assert!(result.is_ok(), "schema {}: {:?}", schema_version, result);
+    }
+
+    let customer =
+      |schema_version| builder.build(schema_version).unwrap()["header"]["customer"].clone();
+    assert_eq!(customer("1.11.0").get("website"), None);
+    assert_eq!(customer("2.0.0")["website"], "example.com");
+  }
+}
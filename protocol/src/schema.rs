@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use serde::Serialize;
+use serde_json::Value;
+use tracing::info;
+use versa::protocol::{misuse::MisuseCode, webhook::TransactionEvent};
+
+/// Schemas embedded into the build as `(schema_version, event, schema)`, copied from the
+/// `versa` crate's own schemas, see `protocol/schemas`
+pub static BUNDLED_SCHEMAS: &[(&str, &str, &str)] = &[
+  (
+    "1.11.0",
+    "itinerary",
+    include_str!("../schemas/1.11.0/itinerary.schema.json"),
+  ),
+  (
+    "1.11.0",
+    "receipt",
+    include_str!("../schemas/1.11.0/receipt.schema.json"),
+  ),
+  (
+    "2.0.0",
+    "itinerary",
+    include_str!("../schemas/2.0.0/itinerary.schema.json"),
+  ),
+  (
+    "2.0.0",
+    "receipt",
+    include_str!("../schemas/2.0.0/receipt.schema.json"),
+  ),
+];
+
+/// Schema versions the bundled `versa` crate can register and send. Update this list
+/// alongside the `versa` dependency.
+pub const SUPPORTED_SCHEMA_VERSIONS: &[&str] = &[
+  "1.5.0", "1.5.1", "1.6.0", "1.7.0", "1.8.0", "1.9.0", "1.10.0", "1.11.0", "2.0.0",
+];
+
+#[derive(Debug)]
+pub enum ValidationFailure {
+  /// The data does not conform to the schema, with one message per violation
+  Invalid {
+    code: MisuseCode,
+    errors: Vec<String>,
+  },
+  /// The schema for the requested version could not be loaded
+  SchemaUnavailable(String),
+}
+
+/// The schema name for an event, e.g. `receipt`, as used in schema file names
+pub fn event_name(event: &TransactionEvent) -> String {
+  event.to_string()
+}
+
+/// Schema versions are full semantic versions, e.g. `1.10.0`
+pub fn is_valid_schema_version(schema_version: &str) -> bool {
+  let parts: Vec<&str> = schema_version.split('.').collect();
+  parts.len() == 3
+    && parts
+      .iter()
+      .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+}
+
+pub fn is_supported_schema_version(schema_version: &str) -> bool {
+  SUPPORTED_SCHEMA_VERSIONS.contains(&schema_version)
+}
+
+/// The schema embedded into the build for `event` at `schema_version`, if any
+pub fn bundled_schema(event: &TransactionEvent, schema_version: &str) -> Option<&'static str> {
+  BUNDLED_SCHEMAS
+    .iter()
+    .find(|(version, name, _)| *version == schema_version && *name == event_name(event))
+    .map(|(_, _, schema)| *schema)
+}
+
+fn is_bundled_version(schema_version: &str) -> bool {
+  BUNDLED_SCHEMAS
+    .iter()
+    .any(|(version, _, _)| *version == schema_version)
+}
+
+/// Fetching schemas for versions that are not bundled is opt-in
+fn remote_lookup_enabled() -> bool {
+  std::env::var("VERSA_SCHEMA_REMOTE_LOOKUP")
+    .map(|val| val == "true")
+    .unwrap_or(false)
+}
+
+/// Loads the bundled schema, or `None` for versions that are not bundled at all
+fn load_schema(
+  event: &TransactionEvent,
+  schema_version: &str,
+) -> Result<Option<Value>, ValidationFailure> {
+  if let Some(schema) = bundled_schema(event, schema_version) {
+    return serde_json::from_str(schema)
+      .map(Some)
+      .map_err(|e| ValidationFailure::SchemaUnavailable(format!("Invalid bundled schema: {}", e)));
+  }
+
+  if is_bundled_version(schema_version) {
+    return Err(ValidationFailure::Invalid {
+      code: MisuseCode::SchemaVersionInvalid,
+      errors: vec![format!(
+        "schema_version {} has no {} schema",
+        schema_version,
+        event_name(event)
+      )],
+    });
+  }
+
+  Ok(None)
+}
+
+/// Validates data at a version that is not bundled with `versa`'s own validator, which
+/// fetches the schema and reports the first violation only. Without remote lookup, or when
+/// the schema cannot be fetched, the schema is unavailable.
+async fn validate_unbundled(
+  event: &TransactionEvent,
+  schema_version: &str,
+  mut errors: Vec<String>,
+  data: &Value,
+) -> Result<(), ValidationFailure> {
+  if !remote_lookup_enabled() {
+    return Err(ValidationFailure::SchemaUnavailable(format!(
+      "No bundled {} schema for schema_version {}",
+      event_name(event),
+      schema_version
+    )));
+  }
+
+  // versa's validator reads the version from the data itself
+  let mut data = data.clone();
+  if let Some(object) = data.as_object_mut() {
+    object
+      .entry("schema_version")
+      .or_insert_with(|| Value::String(schema_version.to_string()));
+  }
+
+  let validator = versa::schema::validator::Validator::new().allow_remote_lookup(true);
+  let code = match validator.validate(event, &data).await {
+    Ok(()) => MisuseCode::SchemaValidationFailed,
+    Err((MisuseCode::SchemaVersionUnknown, message)) => {
+      return Err(ValidationFailure::SchemaUnavailable(format!(
+        "Failed to fetch {} schema for schema_version {}: {}",
+        event_name(event),
+        schema_version,
+        message
+      )));
+    }
+    Err((code, message)) => {
+      errors.push(message);
+      code
+    }
+  };
+
+  if errors.is_empty() {
+    Ok(())
+  } else {
+    Err(ValidationFailure::Invalid { code, errors })
+  }
+}
+
+fn check_schema_version(
+  schema_version: &str,
+  data: &Value,
+) -> Result<Vec<String>, ValidationFailure> {
+  if !is_valid_schema_version(schema_version) {
+    return Err(ValidationFailure::Invalid {
+      code: MisuseCode::SchemaVersionInvalid,
+      errors: vec![format!("Invalid schema_version: {}", schema_version)],
+    });
+  }
+
+  let mut errors = Vec::new();
+  if let Some(data_version) = data.get("schema_version").and_then(Value::as_str) {
+    if data_version != schema_version {
+      errors.push(format!(
+        "schema_version {} does not match the requested schema_version {}",
+        data_version, schema_version
+      ));
+    }
+  }
+  Ok(errors)
+}
+
+fn compile(schema: &Value) -> Result<jsonschema::Validator, ValidationFailure> {
+  jsonschema::validator_for(schema)
+    .map_err(|e| ValidationFailure::SchemaUnavailable(format!("Invalid schema: {}", e)))
+}
+
+fn check_data(
+  validator: &jsonschema::Validator,
+  mut errors: Vec<String>,
+  data: &Value,
+) -> Result<(), ValidationFailure> {
+  // Same messages as `versa`'s validator reports, one per violation
+  errors.extend(validator.iter_errors(data).map(|e| e.to_string()));
+
+  if errors.is_empty() {
+    Ok(())
+  } else {
+    Err(ValidationFailure::Invalid {
+      code: MisuseCode::SchemaValidationFailed,
+      errors,
+    })
+  }
+}
+
+/// Validates `data` against the schema for `event` at `schema_version`, collecting every
+/// violation rather than stopping at the first one. Validation is offline for bundled
+/// schema versions. The schema is compiled on every call, use a [`ValidatorCache`] when
+/// validating repeatedly.
+pub async fn validate_all(
+  event: &TransactionEvent,
+  schema_version: &str,
+  data: &Value,
+) -> Result<(), ValidationFailure> {
+  let errors = check_schema_version(schema_version, data)?;
+  match load_schema(event, schema_version)? {
+    Some(schema) => check_data(&compile(&schema)?, errors, data),
+    None => validate_unbundled(event, schema_version, errors, data).await,
+  }
+}
+
+type ValidatorKey = (String, String);
+
+/// Validation timings for one event and schema version
+#[derive(Clone, Debug, Serialize)]
+pub struct ValidationTimings {
+  pub event: String,
+  pub schema_version: String,
+  pub validations: u64,
+  pub failures: u64,
+  pub mean_micros: u64,
+  pub max_micros: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ValidationMetrics {
+  /// Number of compiled validators held by the cache
+  pub compiled_validators: usize,
+  pub timings: Vec<ValidationTimings>,
+}
+
+#[derive(Default)]
+struct Timings {
+  validations: u64,
+  failures: u64,
+  total_micros: u64,
+  max_micros: u64,
+}
+
+/// Compiled validators keyed by event and schema version, so that each schema is compiled
+/// once rather than on every validation, along with how long validations take
+#[derive(Default)]
+pub struct ValidatorCache {
+  validators: RwLock<HashMap<ValidatorKey, Arc<jsonschema::Validator>>>,
+  timings: Mutex<HashMap<ValidatorKey, Timings>>,
+}
+
+impl ValidatorCache {
+  /// Compiles every bundled schema, returning how many were compiled
+  pub fn warm_up(&self) -> usize {
+    let mut validators = self.validators.write().unwrap();
+    for (version, name, schema) in BUNDLED_SCHEMAS {
+      let compiled = serde_json::from_str(schema)
+        .map_err(|e| ValidationFailure::SchemaUnavailable(e.to_string()))
+        .and_then(|schema| compile(&schema));
+      match compiled {
+        Ok(validator) => {
+          validators.insert((name.to_string(), version.to_string()), Arc::new(validator));
+        }
+        Err(e) => info!(
+          "WARN: Failed to compile bundled {} schema {}: {:?}",
+          name, version, e
+        ),
+      }
+    }
+    info!("Compiled {} bundled schema validators", validators.len());
+    validators.len()
+  }
+
+  /// The compiled validator for a bundled schema, or `None` for versions that are not bundled
+  fn validator(
+    &self,
+    event: &TransactionEvent,
+    schema_version: &str,
+  ) -> Result<Option<Arc<jsonschema::Validator>>, ValidationFailure> {
+    let key = (event_name(event), schema_version.to_string());
+    if let Some(validator) = self.validators.read().unwrap().get(&key) {
+      return Ok(Some(validator.clone()));
+    }
+
+    let schema = match load_schema(event, schema_version)? {
+      Some(schema) => schema,
+      None => return Ok(None),
+    };
+    let validator = Arc::new(compile(&schema)?);
+    self
+      .validators
+      .write()
+      .unwrap()
+      .insert(key, validator.clone());
+    Ok(Some(validator))
+  }
+
+  /// Same as [`validate_all`], reusing the compiled validator and recording the time taken
+  pub async fn validate(
+    &self,
+    event: &TransactionEvent,
+    schema_version: &str,
+    data: &Value,
+  ) -> Result<(), ValidationFailure> {
+    let errors = check_schema_version(schema_version, data)?;
+    let validator = self.validator(event, schema_version)?;
+
+    let started_at = Instant::now();
+    let result = match validator {
+      Some(validator) => check_data(&validator, errors, data),
+      None => validate_unbundled(event, schema_version, errors, data).await,
+    };
+    let micros = started_at.elapsed().as_micros() as u64;
+
+    let mut timings = self.timings.lock().unwrap();
+    let entry = timings
+      .entry((event_name(event), schema_version.to_string()))
+      .or_default();
+    entry.validations += 1;
+    entry.failures += result.is_err() as u64;
+    entry.total_micros += micros;
+    entry.max_micros = entry.max_micros.max(micros);
+    result
+  }
+
+  pub fn metrics(&self) -> ValidationMetrics {
+    let mut timings: Vec<ValidationTimings> = self
+      .timings
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|((event, schema_version), timings)| ValidationTimings {
+        event: event.clone(),
+        schema_version: schema_version.clone(),
+        validations: timings.validations,
+        failures: timings.failures,
+        mean_micros: timings.total_micros / timings.validations.max(1),
+        max_micros: timings.max_micros,
+      })
+      .collect();
+    timings.sort_by(|a, b| (&a.event, &a.schema_version).cmp(&(&b.event, &b.schema_version)));
+
+    ValidationMetrics {
+      compiled_validators: self.validators.read().unwrap().len(),
+      timings,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_schema_version_format() {
+    assert!(is_valid_schema_version("1.10.0"));
+    assert!(is_valid_schema_version("2.0.0"));
+    assert!(!is_valid_schema_version("1.0"));
+    assert!(!is_valid_schema_version("1.x.0"));
+    assert!(!is_valid_schema_version("1..0"));
+  }
+
+  #[test]
+  fn test_event_name_should_match_schema_file_names() {
+    assert_eq!(event_name(&TransactionEvent::Receipt), "receipt");
+    assert_eq!(event_name(&TransactionEvent::Itinerary), "itinerary");
+  }
+
+  #[test]
+  fn test_supported_schema_versions_are_valid() {
+    assert!(SUPPORTED_SCHEMA_VERSIONS
+      .iter()
+      .all(|version| is_valid_schema_version(version)));
+    assert!(is_supported_schema_version("1.8.0"));
+    assert!(is_supported_schema_version("2.0.0"));
+    assert!(!is_supported_schema_version("0.9.0"));
+  }
+
+  #[tokio::test]
+  async fn test_validator_cache_should_reuse_validator_and_record_timings() {
+    let cache = ValidatorCache::default();
+    let schema = serde_json::json!({
+      "type": "object",
+      "required": ["header"]
+    });
+    cache.validators.write().unwrap().insert(
+      ("receipt".into(), "1.10.0".into()),
+      Arc::new(compile(&schema).unwrap()),
+    );
+
+    let event = TransactionEvent::Receipt;
+    let valid = serde_json::json!({ "schema_version": "1.10.0", "header": {} });
+    let invalid = serde_json::json!({});
+    assert!(cache.validate(&event, "1.10.0", &valid).await.is_ok());
+    assert!(cache.validate(&event, "1.10.0", &invalid).await.is_err());
+
+    let metrics = cache.metrics();
+    assert_eq!(metrics.compiled_validators, 1);
+    assert_eq!(metrics.timings.len(), 1);
+    assert_eq!(metrics.timings[0].validations, 2);
+    assert_eq!(metrics.timings[0].failures, 1);
+  }
+
+  #[tokio::test]
+  async fn test_warm_up_should_compile_every_bundled_schema() {
+    let cache = ValidatorCache::default();
+    assert_eq!(cache.warm_up(), 4);
+    assert_eq!(cache.metrics().compiled_validators, BUNDLED_SCHEMAS.len());
+
+    let itinerary = serde_json::json!({
+      "schema_version": "2.0.0",
+      "header": { "subtotal": 1780 },
+      "itemization": {},
+      "footer": {}
+    });
+    let Err(ValidationFailure::Invalid { code, errors }) = cache
+      .validate(&TransactionEvent::Itinerary, "2.0.0", &itinerary)
+      .await
+    else {
+      panic!("The itinerary should fail validation against the bundled schema");
+    };
+    assert_eq!(code, MisuseCode::SchemaValidationFailed);
+    assert_eq!(
+      errors,
+      vec!["Additional properties are not allowed ('subtotal' was unexpected)".to_string()]
+    );
+  }
+}
@@ -0,0 +1,60 @@
+/// Runs a command given on the command line instead of the server, returning false when
+/// no command was given
+pub async fn run(args: &[String]) -> bool {
+  match args.first().map(String::as_str) {
+    #[cfg(any(feature = "sender", feature = "receiver"))]
+    Some("import-customers") => {
+      import_customers(&args[1..]).await;
+      true
+    }
+    _ => false,
+  }
+}
+
+/// `import-customers <sender|receiver> <input.csv|input.jsonl> [report.jsonl]`
+///
+/// Writes a JSONL report with one result per row, which can itself be passed as the input
+/// to retry only the rows that failed.
+#[cfg(any(feature = "sender", feature = "receiver"))]
+async fn import_customers(args: &[String]) {
+  let (role, input_path) = match args {
+    [role, input_path, ..] => (role.as_str(), input_path),
+    _ => {
+      eprintln!("Usage: import-customers <sender|receiver> <input.csv|input.jsonl> [report.jsonl]");
+      std::process::exit(2);
+    }
+  };
+  let report_path = args
+    .get(2)
+    .cloned()
+    .unwrap_or_else(|| format!("{}.report.jsonl", input_path));
+
+  let input = std::fs::read_to_string(input_path).expect("Failed to read import file");
+  let csv = input_path.ends_with(".csv");
+
+  let report = match role {
+    #[cfg(feature = "sender")]
+    "sender" => {
+      let customers = api_sender::open_customer_ledger();
+      api_sender::routes::customers::import_customers(&customers, &input, csv).await
+    }
+    #[cfg(feature = "receiver")]
+    "receiver" => {
+      let customers = api_receiver::open_customer_ledger();
+      api_receiver::customers::import_customers(&customers, &input, csv).await
+    }
+    _ => {
+      eprintln!("Unknown or disabled role: {}", role);
+      std::process::exit(2);
+    }
+  };
+
+  std::fs::write(&report_path, report.to_jsonl()).expect("Failed to write import report");
+  println!(
+    "Registered {}, failed {}, skipped {}. Report written to {}",
+    report.registered, report.failed, report.skipped, report_path
+  );
+  if report.failed > 0 {
+    std::process::exit(1);
+  }
+}
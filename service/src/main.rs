@@ -7,6 +7,7 @@ use tower_http::trace::TraceLayer;
 use tower_request_id::{RequestId, RequestIdLayer};
 use tracing::{info, info_span, Level};
 
+mod cli;
 mod middleware;
 mod service_info;
 
@@ -15,6 +16,11 @@ async fn main() {
   dotenv::dotenv().ok();
   tracing_subscriber::fmt().with_max_level(Level::INFO).init();
 
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  if cli::run(&args).await {
+    return;
+  }
+
   let mut app = Router::new().route("/", get(service_info::service_info));
 
   #[cfg(feature = "receiver")]
@@ -12,3 +12,43 @@ pub fn get_client_string() -> String {
     std::env::var("IMAGE_VERSION").unwrap_or("".into())
   )
 }
+
+pub fn get_env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+  std::env::var(key)
+    .ok()
+    .and_then(|val| val.parse().ok())
+    .unwrap_or(default)
+}
+
+pub fn unix_timestamp() -> i64 {
+  std::time::SystemTime::now()
+    .duration_since(std::time::SystemTime::UNIX_EPOCH)
+    .unwrap()
+    .as_secs() as i64
+}
+
+/// Longest delay between two retries of a failed delivery
+pub const MAX_RETRY_DELAY_SECS: i64 = 3600;
+
+/// Delay before the next attempt at a delivery that failed `attempts` times, doubling from
+/// `retry_base_secs` up to [`MAX_RETRY_DELAY_SECS`]
+pub fn retry_delay(retry_base_secs: i64, attempts: u32) -> i64 {
+  let exponent = attempts.saturating_sub(1).min(16);
+  retry_base_secs
+    .saturating_mul(1 << exponent)
+    .min(MAX_RETRY_DELAY_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_retry_delay_should_grow_exponentially_up_to_cap() {
+    assert_eq!(retry_delay(30, 1), 30);
+    assert_eq!(retry_delay(30, 2), 60);
+    assert_eq!(retry_delay(30, 4), 240);
+    assert_eq!(retry_delay(30, 40), MAX_RETRY_DELAY_SECS);
+  }
+}